@@ -0,0 +1,435 @@
+// Stores the contents of every blob once, keyed by its git sha, so that the same file in forks,
+// branches and vendored copies is only downloaded and stored once. The files in the repo
+// directories are hard links to the blobs in the store.
+//
+// If the store has a size limit, the least recently used blobs are evicted when it is exceeded.
+// Each blob has a <blob>.links file which lists the repo files linked to it, so that they can be
+// turned back into placeholders when it is evicted.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use serde_json::json;
+use walkdir::WalkDir;
+use crate::error::{GitFSError, Result};
+use crate::journal;
+use crate::locks::{KeyedLocks};
+use crate::repo_id::{RepoId};
+
+// A repo file which is linked to a blob.
+pub struct Link {
+    pub repo: RepoId,
+    // The path of the file inside of the repo.
+    pub path: String,
+    local_path: String,
+}
+
+impl Link {
+    pub fn new(repo: &RepoId, path: &str, local_path: &str) -> Link {
+        Link{
+            repo: repo.clone(),
+            path: path.to_string(),
+            local_path: local_path.to_string(),
+        }
+    }
+
+    // Returns the directory whose repos directory holds the file.
+    pub fn state_dir(&self) -> Option<&str> {
+        self.local_path.strip_suffix(&format!("/repos/{}/{}", self.repo, self.path))
+    }
+}
+
+// Called for each repo file whose blob is evicted, before the file is replaced by a placeholder.
+type EvictedFn = dyn Fn(&Link) -> Result<()> + Send + Sync;
+
+struct BlobEntry {
+    size: u64,
+    ino: u64,
+    last_used: u64,
+}
+
+// The state needed for LRU eviction. It is shared by every clone of the store.
+struct Lru {
+    // The maximum total size of the blobs in bytes, or None if there is no limit.
+    limit: Option<u64>,
+    evicted: Option<Arc<EvictedFn>>,
+    total_size: u64,
+    // Maps a blob path to its entry.
+    blobs: HashMap<String, BlobEntry>,
+    // Maps the inode of a blob to its path, so that a repo file can be traced back to its blob.
+    inodes: HashMap<u64, String>,
+    // Maps a use counter value to the blob which was used at that point.
+    by_use: BTreeMap<u64, String>,
+    counter: u64,
+}
+
+impl Lru {
+    fn add(&mut self, blob_path: &str, size: u64, ino: u64) {
+        if self.blobs.contains_key(blob_path) {
+            return self.touch(blob_path);
+        }
+        self.counter += 1;
+        self.total_size += size;
+        self.inodes.insert(ino, blob_path.to_string());
+        self.by_use.insert(self.counter, blob_path.to_string());
+        self.blobs.insert(blob_path.to_string(), BlobEntry{ size: size, ino: ino, last_used: self.counter });
+    }
+
+    fn touch(&mut self, blob_path: &str) {
+        self.counter += 1;
+        if let Some(entry) = self.blobs.get_mut(blob_path) {
+            self.by_use.remove(&entry.last_used);
+            entry.last_used = self.counter;
+            self.by_use.insert(self.counter, blob_path.to_string());
+        }
+    }
+
+    fn remove(&mut self, blob_path: &str) {
+        if let Some(entry) = self.blobs.remove(blob_path) {
+            self.total_size -= entry.size;
+            self.inodes.remove(&entry.ino);
+            self.by_use.remove(&entry.last_used);
+        }
+    }
+
+    // Returns the least recently used blob if the store is over its limit. The most recently
+    // used blob is never evicted, as it is the one which is being accessed.
+    fn next_eviction(&self) -> Option<String> {
+        if self.total_size <= self.limit? || self.blobs.len() < 2 {
+            return None;
+        }
+        self.by_use.values().next().cloned()
+    }
+}
+
+#[derive(Clone)]
+pub struct BlobStore {
+    dir: String,
+    lru: Arc<Mutex<Lru>>,
+    // Held while a blob is fetched, linked or evicted, so that a blob is only fetched once and is
+    // never evicted while it is being linked.
+    blob_locks: Arc<KeyedLocks>,
+    // Held by the thread which is evicting blobs.
+    evicting: Arc<Mutex<()>>,
+}
+
+impl BlobStore {
+    pub fn new(cache_dir: &str) -> BlobStore {
+        BlobStore{
+            dir: format!("{}/blobs", cache_dir),
+            lru: Arc::new(Mutex::new(Lru{
+                limit: None,
+                evicted: None,
+                total_size: 0,
+                blobs: HashMap::new(),
+                inodes: HashMap::new(),
+                by_use: BTreeMap::new(),
+                counter: 0,
+            })),
+            blob_locks: Arc::new(KeyedLocks::new()),
+            evicting: Arc::new(Mutex::new(())),
+        }
+    }
+
+    // Limits the total size of the store. The blobs which are already in the store are loaded,
+    // and are treated as last used in the order they were fetched. evicted is called for each
+    // repo file whose blob is evicted, before the file is replaced by a placeholder.
+    pub fn set_limit<F>(&self, limit: u64, evicted: F) -> Result<()>
+        where F: Fn(&Link) -> Result<()> + Send + Sync + 'static {
+        fs::create_dir_all(&self.dir)?;
+        let mut blobs = Vec::new();
+        for entry in WalkDir::new(&self.dir).min_depth(2).max_depth(2) {
+            let entry = entry.map_err(io::Error::from)?;
+            let name = entry.file_name().to_str().ok_or(GitFSError::NoneError)?;
+            if name.contains(".") && !name.ends_with(".x") {
+                continue;
+            }
+            let metadata = entry.metadata().map_err(io::Error::from)?;
+            blobs.push((metadata.mtime(), entry.path().to_str().ok_or(GitFSError::NoneError)?.to_string(), metadata.len(), metadata.ino()));
+        }
+        blobs.sort();
+        {
+            let mut lru = self.lru.lock().unwrap();
+            lru.limit = Some(limit);
+            lru.evicted = Some(Arc::new(evicted));
+            for (_mtime, blob_path, size, ino) in blobs {
+                lru.add(&blob_path, size, ino);
+            }
+            println!("Loaded {} blobs ({} bytes) into the cache", lru.blobs.len(), lru.total_size);
+        }
+        self.evict()
+    }
+
+    // Returns the path of a blob in the store. Links share their mode, so executable files are
+    // stored separately from regular files.
+    fn blob_path(&self, sha: &str, executable: bool) -> String {
+        let suffix = if executable { ".x" } else { "" };
+        format!("{}/{}/{}{}", self.dir, &sha[..2], &sha[2..], suffix)
+    }
+
+    // Replaces the placeholder at link.local_path with a link to the blob. If the store does not
+    // have the blob yet then fetch is called to write it to the given path. Other blobs are then
+    // evicted if the store is over its limit, whether the file is being read or prefetched.
+    pub fn link<F>(&self, sha: &str, link: Link, fetch: F) -> Result<()>
+        where F: FnOnce(&str) -> Result<()> {
+        let local_path = &link.local_path;
+        let mode = fs::metadata(local_path).map(|m| m.permissions().mode()).unwrap_or(0o644);
+        let blob_path = self.blob_path(sha, mode & 0o111 != 0);
+        self.blob_locks.with_lock(&blob_path, || -> Result<()> {
+            if !Path::new(&blob_path).exists() {
+                fs::create_dir_all(Path::new(&blob_path).parent().ok_or(GitFSError::NoneError)?)?;
+                // Download to a temporary path so that a partial blob is never in the store.
+                let tmp_path = format!("{}.tmp", blob_path);
+                fetch(&tmp_path)?;
+                fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode))?;
+                fs::rename(&tmp_path, &blob_path)?;
+            } else {
+                println!("Blob {} is already in the store", sha);
+            }
+            // Record the link first so that the file is never linked without the store knowing.
+            let entry = json!({
+                "host": link.repo.host,
+                "owner": link.repo.owner,
+                "repo": link.repo.dir_name,
+                "path": link.path,
+                "local_path": local_path,
+            });
+            journal::append(&mut journal::open(&format!("{}.links", blob_path))?, &entry)?;
+            // The link is created inside the store and moved into place, as any name next to
+            // local_path could be another file of the repo.
+            let link_path = format!("{}.link", blob_path);
+            if let Err(e) = fs::hard_link(&blob_path, &link_path) {
+                println!("Unable to link {} ({}), copying it instead", blob_path, e);
+                fs::copy(&blob_path, &link_path)?;
+            }
+            fs::rename(&link_path, local_path)?;
+            let metadata = fs::metadata(&blob_path)?;
+            self.lru.lock().unwrap().add(&blob_path, metadata.len(), metadata.ino());
+            Ok(())
+        })?;
+        self.evict()
+    }
+
+    // Marks the blob which a repo file is linked to as used.
+    pub fn touch(&self, path: &Path) {
+        if let Ok(metadata) = fs::metadata(path) {
+            let mut lru = self.lru.lock().unwrap();
+            if let Some(blob_path) = lru.inodes.get(&metadata.ino()).cloned() {
+                lru.touch(&blob_path);
+            }
+        }
+    }
+
+    // Evicts the least recently used blobs until the store is within its limit. Every repo file
+    // which is still linked to an evicted blob is replaced by a placeholder of the same size.
+    fn evict(&self) -> Result<()> {
+        // Only one thread needs to evict at a time.
+        let _evicting = match self.evicting.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return Ok(()),
+        };
+        loop {
+            let (blob_path, evicted) = {
+                let lru = self.lru.lock().unwrap();
+                match (lru.next_eviction(), lru.evicted.clone()) {
+                    (Some(blob_path), Some(evicted)) => (blob_path, evicted),
+                    _ => return Ok(()),
+                }
+            };
+            self.blob_locks.with_lock(&blob_path, || -> Result<()> {
+                println!("Evicting {}", blob_path);
+                let metadata = match fs::metadata(&blob_path) {
+                    Ok(metadata) => metadata,
+                    // The blob was removed from outside of the filesystem.
+                    Err(_) => {
+                        self.lru.lock().unwrap().remove(&blob_path);
+                        return Ok(());
+                    },
+                };
+                let links_path = format!("{}.links", blob_path);
+                for link in read_links(&links_path)? {
+                    match fs::metadata(&link.local_path) {
+                        // The file was modified or replaced, so it no longer uses the blob.
+                        Ok(m) if m.ino() != metadata.ino() => continue,
+                        Err(_) => continue,
+                        _ => {},
+                    }
+                    evicted(&link)?;
+                    let placeholder_path = format!("{}.placeholder", blob_path);
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .write(true)
+                        .truncate(true)
+                        .mode(metadata.permissions().mode())
+                        .open(&placeholder_path)?;
+                    file.set_len(metadata.len())?;
+                    fs::rename(&placeholder_path, &link.local_path)?;
+                }
+                fs::remove_file(&blob_path)?;
+                if let Err(e) = fs::remove_file(&links_path) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e.into());
+                    }
+                }
+                self.lru.lock().unwrap().remove(&blob_path);
+                Ok(())
+            })?;
+        }
+    }
+
+    // Gives a file which may be linked to the store its own copy, so that modifying it does not
+    // modify the same file in every other repo.
+    pub fn unshare(&self, path: &Path) -> io::Result<()> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if !metadata.is_file() || metadata.nlink() < 2 {
+            return Ok(());
+        }
+        let copy_path = format!("{}/{}.unshare", self.dir, metadata.ino());
+        fs::copy(path, &copy_path)?;
+        fs::rename(&copy_path, path)
+    }
+}
+
+fn read_links(links_path: &str) -> Result<Vec<Link>> {
+    let mut links = Vec::new();
+    journal::replay(links_path, "link", |entry| {
+        let field = |name: &str| entry[name].as_str().unwrap_or("").to_string();
+        links.push(Link{
+            repo: RepoId::new(&field("host"), &field("owner"), &field("repo")),
+            path: field("path"),
+            local_path: field("local_path"),
+        });
+        Some(())
+    })?;
+    Ok(links)
+}
+
+// Parses a size such as 1048576, 512M or 10G.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (number, multiplier) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 1 << 10),
+        'M' => (&size[..size.len() - 1], 1 << 20),
+        'G' => (&size[..size.len() - 1], 1 << 30),
+        'T' => (&size[..size.len() - 1], 1 << 40),
+        _ => (size, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lru(limit: Option<u64>) -> Lru {
+        Lru{
+            limit: limit,
+            evicted: None,
+            total_size: 0,
+            blobs: HashMap::new(),
+            inodes: HashMap::new(),
+            by_use: BTreeMap::new(),
+            counter: 0,
+        }
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1048576"), Some(1048576));
+        assert_eq!(parse_size("4k"), Some(4 << 10));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("10G"), Some(10 << 30));
+        assert_eq!(parse_size("2T"), Some(2 << 40));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("10X"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("99999999999T"), None);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_blob() {
+        let mut lru = lru(Some(25));
+        lru.add("a", 10, 1);
+        lru.add("b", 10, 2);
+        assert_eq!(lru.next_eviction(), None);
+        lru.add("c", 10, 3);
+        assert_eq!(lru.total_size, 30);
+        assert_eq!(lru.next_eviction(), Some("a".to_string()));
+        lru.touch("a");
+        assert_eq!(lru.next_eviction(), Some("b".to_string()));
+        lru.remove("b");
+        assert_eq!(lru.total_size, 20);
+        assert_eq!(lru.next_eviction(), None);
+    }
+
+    #[test]
+    fn adding_a_blob_again_touches_it() {
+        let mut lru = lru(Some(15));
+        lru.add("a", 10, 1);
+        lru.add("b", 10, 2);
+        lru.add("a", 10, 1);
+        assert_eq!(lru.total_size, 20);
+        assert_eq!(lru.next_eviction(), Some("b".to_string()));
+    }
+
+    #[test]
+    fn never_evicts_the_only_blob() {
+        let mut lru = lru(Some(5));
+        lru.add("a", 10, 1);
+        assert_eq!(lru.next_eviction(), None);
+    }
+
+    #[test]
+    fn does_not_evict_without_a_limit() {
+        let mut lru = lru(None);
+        lru.add("a", 10, 1);
+        lru.add("b", 10, 2);
+        assert_eq!(lru.next_eviction(), None);
+    }
+
+    #[test]
+    fn removes_inodes_with_blobs() {
+        let mut lru = lru(None);
+        lru.add("a", 10, 1);
+        assert_eq!(lru.inodes.get(&1), Some(&"a".to_string()));
+        lru.remove("a");
+        assert!(lru.inodes.is_empty());
+        assert!(lru.by_use.is_empty());
+        assert_eq!(lru.total_size, 0);
+    }
+
+    #[test]
+    fn evicts_when_a_blob_is_linked() {
+        let dir = tempdir::TempDir::new("vg").unwrap();
+        let store = BlobStore::new(dir.path().to_str().unwrap());
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let evicted_paths = evicted.clone();
+        store.set_limit(10, move |link| {
+            evicted_paths.lock().unwrap().push(link.path.clone());
+            Ok(())
+        }).unwrap();
+        let repo = RepoId::new("github.com", "octocat", "hello");
+        let repo_dir = dir.path().join("repos/github.com/octocat/hello");
+        fs::create_dir_all(&repo_dir).unwrap();
+        for (name, sha) in &[("a", "aaaa"), ("b", "bbbb")] {
+            let local_path = repo_dir.join(name);
+            let link = Link::new(&repo, name, local_path.to_str().unwrap());
+            store.link(sha, link, |blob_path| Ok(fs::write(blob_path, b"12345678")?)).unwrap();
+        }
+        assert_eq!(*evicted.lock().unwrap(), vec!["a"]);
+        assert_eq!(fs::read(repo_dir.join("a")).unwrap(), vec![0; 8]);
+        assert_eq!(fs::read(repo_dir.join("b")).unwrap(), b"12345678");
+    }
+}
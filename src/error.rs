@@ -1,6 +1,5 @@
 use std::error;
 use std::fmt;
-use reqwest;
 use reqwest::{StatusCode};
 
 pub type Result<T> = std::result::Result<T, GitFSError>;
@@ -24,11 +23,14 @@ impl std::error::Error for InternalError {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum GitFSError {
     IOError(std::io::Error),
     ReqwestError(reqwest::Error),
+    Git2Error(git2::Error),
     InternalError(InternalError),
-    NoneError(std::option::NoneError),
+    // A value which was expected to be there was missing.
+    NoneError,
 }
 
 impl GitFSError {
@@ -42,7 +44,7 @@ impl GitFSError {
     pub fn code(&self) -> i32 {
         match *self {
             GitFSError::IOError(ref e) => {
-                return e.raw_os_error().unwrap_or(libc::EIO);
+                e.raw_os_error().unwrap_or(libc::EIO)
             },
             GitFSError::ReqwestError(ref e) => {
                 let status = e.status();
@@ -52,7 +54,8 @@ impl GitFSError {
                 match e.status().unwrap() {
                     StatusCode::OK => 0,
                     StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => libc::EPERM,
-                    StatusCode::NOT_FOUND => libc::ENOENT,
+                    // Github answers with a 422 when a ref or sha does not name a commit.
+                    StatusCode::NOT_FOUND | StatusCode::UNPROCESSABLE_ENTITY => libc::ENOENT,
                     StatusCode::REQUEST_TIMEOUT => libc::ETIMEDOUT,
                     StatusCode::PRECONDITION_FAILED => libc::EINVAL,
                     StatusCode::PAYLOAD_TOO_LARGE => libc::EFBIG,
@@ -65,10 +68,18 @@ impl GitFSError {
                     }
                 }
             },
+            GitFSError::Git2Error(ref e) => {
+                match e.code() {
+                    git2::ErrorCode::NotFound => libc::ENOENT,
+                    git2::ErrorCode::Exists => libc::EEXIST,
+                    git2::ErrorCode::Auth => libc::EPERM,
+                    _ => libc::EIO,
+                }
+            },
             GitFSError::InternalError(ref e) => {
                 e.code
             },
-            GitFSError::NoneError(_) => {
+            GitFSError::NoneError => {
                 // TODO: There may be a better code for this case.
                 libc::EIO
             },
@@ -81,10 +92,9 @@ impl fmt::Display for GitFSError {
         match *self {
             GitFSError::IOError(ref e) => e.fmt(f),
             GitFSError::ReqwestError(ref e) => e.fmt(f),
+            GitFSError::Git2Error(ref e) => e.fmt(f),
             GitFSError::InternalError(ref e) => e.fmt(f),
-            GitFSError::NoneError(ref e) => {
-                write!(f, "None found: {:?}", e)
-            },
+            GitFSError::NoneError => write!(f, "None found"),
         }
     }
 }
@@ -94,8 +104,9 @@ impl error::Error for GitFSError {
         match *self {
             GitFSError::IOError(ref e) => Some(e),
             GitFSError::ReqwestError(ref e) => Some(e),
+            GitFSError::Git2Error(ref e) => Some(e),
             GitFSError::InternalError(ref e) => Some(e),
-            GitFSError::NoneError(_) => None,
+            GitFSError::NoneError => None,
         }
     }
 }
@@ -115,8 +126,8 @@ impl From<reqwest::Error> for GitFSError {
     }
 }
 
-impl From<std::option::NoneError> for GitFSError {
-    fn from(err: std::option::NoneError) -> GitFSError {
-        GitFSError::NoneError(err)
+impl From<git2::Error> for GitFSError {
+    fn from(err: git2::Error) -> GitFSError {
+        GitFSError::Git2Error(err)
     }
 }
@@ -10,18 +10,56 @@ use std::path::{Path, PathBuf};
 use crate::libc_extras::libc;
 use crate::libc_wrappers;
 use crate::git::{GitFS};
+use crate::github::{GithubFS, GITHUB_API_URL};
+use crate::error::{Result as GitFSResult};
+use crate::retry::{RetryPolicy};
 
 use fuse_mt::*;
 use time::*;
+use chrono::{DateTime, Utc};
+
+// The settings which are given on the command line.
+pub struct Options {
+    // Every repo is shown as it was at this time instead of when the filesystem was mounted.
+    pub timestamp: Option<DateTime<Utc>>,
+    // The maximum size of the cached file contents in bytes.
+    pub cache_limit: Option<u64>,
+    // The number of levels of subdirectories which are listed in the background, or 0.
+    pub prefetch_depth: usize,
+    // Files up to this size are fetched in the background along with their directories.
+    pub prefetch_size: u64,
+    // Use the Github GraphQL API instead of the REST API.
+    pub github_graphql: bool,
+    pub github_url: String,
+    // How failed requests are retried, and when they time out.
+    pub retry: RetryPolicy,
+    // Only serve what is already in the cache.
+    pub offline: bool,
+    // The git servers which are reached over plain HTTP.
+    pub http_hosts: Vec<String>,
+}
 
-use lazy_static::lazy_static; // 1.4.0
-use std::sync::Mutex;
-
-lazy_static! {
-    static ref GIT: Mutex<GitFS> = Mutex::new(GitFS::new());
+impl Default for Options {
+    fn default() -> Options {
+        Options{
+            timestamp: None,
+            cache_limit: None,
+            prefetch_depth: 0,
+            prefetch_size: 0,
+            github_graphql: false,
+            github_url: GITHUB_API_URL.to_string(),
+            retry: RetryPolicy::default(),
+            offline: false,
+            http_hosts: Vec::new(),
+        }
+    }
 }
 
-pub struct PassthroughFS {}
+pub struct PassthroughFS {
+    // Shared by every FUSE thread. It does its own locking so that a slow download only blocks
+    // the requests which need it.
+    git: GitFS,
+}
 
 fn mode_to_filetype(mode: libc::mode_t) -> FileType {
     match mode & libc::S_IFMT {
@@ -44,9 +82,9 @@ fn stat_to_fuse(stat: libc::stat64) -> FileAttr {
     FileAttr {
         size: stat.st_size as u64,
         blocks: stat.st_blocks as u64,
-        atime: Timespec { sec: stat.st_atime as i64, nsec: stat.st_atime_nsec as i32 },
-        mtime: Timespec { sec: stat.st_mtime as i64, nsec: stat.st_mtime_nsec as i32 },
-        ctime: Timespec { sec: stat.st_ctime as i64, nsec: stat.st_ctime_nsec as i32 },
+        atime: Timespec { sec: stat.st_atime, nsec: stat.st_atime_nsec as i32 },
+        mtime: Timespec { sec: stat.st_mtime, nsec: stat.st_mtime_nsec as i32 },
+        ctime: Timespec { sec: stat.st_ctime, nsec: stat.st_ctime_nsec as i32 },
         crtime: Timespec { sec: 0, nsec: 0 },
         kind,
         perm,
@@ -75,11 +113,11 @@ fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
 #[cfg(target_os = "linux")]
 fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
     Statfs {
-        blocks: statfs.f_blocks as u64,
-        bfree: statfs.f_bfree as u64,
-        bavail: statfs.f_bavail as u64,
-        files: statfs.f_files as u64,
-        ffree: statfs.f_ffree as u64,
+        blocks: statfs.f_blocks,
+        bfree: statfs.f_bfree,
+        bavail: statfs.f_bavail,
+        files: statfs.f_files,
+        ffree: statfs.f_ffree,
         bsize: statfs.f_bsize as u32,
         namelen: statfs.f_namelen as u32,
         frsize: statfs.f_frsize as u32,
@@ -87,11 +125,24 @@ fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
 }
 
 impl PassthroughFS {
-    pub fn new(token: String, cache_dir: String) -> PassthroughFS {
-        let mut git = GIT.lock().unwrap();
-        git.set_token(token);
-        git.set_cache_dir(cache_dir);
-        return PassthroughFS{};
+    pub fn new(token: String, cache_dir: String, options: Options) -> GitFSResult<PassthroughFS> {
+        let mut git = GitFS::new();
+        git.set_cache_dir(cache_dir.clone());
+        if let Some(timestamp) = options.timestamp {
+            git.set_timestamp(timestamp);
+        }
+        if let Some(cache_limit) = options.cache_limit {
+            git.set_cache_limit(cache_limit)?;
+        }
+        if options.prefetch_depth > 0 {
+            git.set_prefetch(options.prefetch_depth, options.prefetch_size);
+        }
+        git.set_retry_policy(options.retry.clone());
+        git.set_http_hosts(options.http_hosts);
+        git.set_offline(options.offline);
+        let github = GithubFS::new(token, &cache_dir, &options.github_url, options.github_graphql, options.retry);
+        git.register_provider("github.com", Box::new(github))?;
+        Ok(PassthroughFS{ git: git })
     }
     fn real_path(&self, partial: &Path) -> Result<OsString, i32> {
         self.real_path_with_opts(partial, false, true)
@@ -100,9 +151,7 @@ impl PassthroughFS {
     fn real_path_with_opts(&self, partial: &Path, ignore_base: bool, is_stat: bool) -> Result<OsString, i32> {
         debug!("partial path: {:?}", partial);
         let partial = partial.strip_prefix("/").unwrap();
-        // TODO: Is an unlock needed?
-        let mut git = GIT.lock().unwrap();
-        match git.clone_if_not_exist(
+        match self.git.clone_if_not_exist(
             partial.to_str().unwrap().to_string(),
             ignore_base,
             is_stat,
@@ -115,6 +164,70 @@ impl PassthroughFS {
         }
     }
 
+    // Returns the real path of a file which is about to be modified.
+    fn writable_path(&self, partial: &Path) -> Result<OsString, i32> {
+        let partial = partial.strip_prefix("/").unwrap();
+        match self.git.writable_path(partial.to_str().unwrap().to_string()) {
+            Ok(s) => Ok(OsString::from(s)),
+            Err(e) => {
+                println!("writable path err = {:?}", e);
+                Err(e.code())
+            }
+        }
+    }
+
+    // Returns the real path at which name is created in parent. New files and directories of a
+    // repo go to its overlay, anything else is created next to the real parent.
+    fn new_path(&self, parent: &Path, name: &OsStr, ignore_base: bool, is_stat: bool) -> Result<PathBuf, i32> {
+        let partial = parent.join(name);
+        let partial = partial.strip_prefix("/").unwrap();
+        match self.git.create_path(partial.to_str().unwrap()) {
+            Ok(Some(s)) => Ok(PathBuf::from(s)),
+            Ok(None) => Ok(PathBuf::from(self.real_path_with_opts(parent, ignore_base, is_stat)?).join(name)),
+            Err(e) => {
+                println!("new path err = {:?}", e);
+                Err(e.code())
+            }
+        }
+    }
+
+    // Removes name from parent if it is in a repo, where it is removed from the overlay. Returns
+    // false if it has to be removed from the real parent instead.
+    fn remove(&self, parent: &Path, name: &OsStr, is_dir: bool) -> Result<bool, i32> {
+        let partial = parent.join(name);
+        let partial = partial.strip_prefix("/").unwrap();
+        self.git.remove(partial.to_str().unwrap(), is_dir).map_err(|e| {
+            error!("remove({:?}): {:?}", partial, e);
+            e.code()
+        })
+    }
+
+    // Makes sure that fh refers to the real contents of a file rather than to a placeholder. This
+    // is normally done when the file is opened, but the placeholder may have been reached in
+    // another way.
+    fn materialize(&self, path: &Path, fh: u64) -> Result<(), libc::c_int> {
+        let partial = path.strip_prefix("/").unwrap().to_str().unwrap();
+        if !self.git.is_placeholder(partial) {
+            return Ok(());
+        }
+        println!("    Fetching placeholder before reading {:?}", path);
+        let real = self.real_path_with_opts(path, true, false)?;
+        // Swap the real file in behind fh, keeping its access mode.
+        let flags = unsafe { libc::fcntl(fh as libc::c_int, libc::F_GETFL) };
+        if flags == -1 {
+            return Err(io::Error::last_os_error().raw_os_error().unwrap());
+        }
+        let real_fh = libc_wrappers::open(real, flags & (libc::O_ACCMODE | libc::O_APPEND))?;
+        let result = unsafe { libc::dup2(real_fh as libc::c_int, fh as libc::c_int) };
+        let e = io::Error::last_os_error();
+        libc_wrappers::close(real_fh)?;
+        if result == -1 {
+            error!("dup2({:?}): {}", path, e);
+            return Err(e.raw_os_error().unwrap());
+        }
+        Ok(())
+    }
+
     fn stat_real_with_opts(&self, path: &Path, ignore_base: bool, is_stat: bool) -> io::Result<FileAttr> {
         let real = match self.real_path_with_opts(path, ignore_base, is_stat) {
             Ok(p) => p,
@@ -211,7 +324,7 @@ impl FilesystemMT for PassthroughFS {
                             warn!("FUSE doesn't support Socket file type; translating to NamedPipe instead.");
                             FileType::NamedPipe
                         },
-                        0 | _ => {
+                        _ => {
                             let entry_path = PathBuf::from(path).join(&name);
                             let real_path = self.real_path(&entry_path)?;
                             match libc_wrappers::lstat(real_path) {
@@ -238,13 +351,47 @@ impl FilesystemMT for PassthroughFS {
             }
         }
 
+        // The entries of a repo directory are changed by its overlay.
+        let partial = path.strip_prefix("/").unwrap().to_str().unwrap();
+        match self.git.overlay_entries(partial) {
+            Ok(Some((upper_dir, deleted))) => {
+                entries.retain(|entry| !deleted.contains(entry.name.to_str().unwrap_or("")));
+                if let Ok(upper_entries) = fs::read_dir(&upper_dir) {
+                    for upper_entry in upper_entries {
+                        let upper_entry = upper_entry.map_err(|e| e.raw_os_error().unwrap())?;
+                        let name = upper_entry.file_name();
+                        if entries.iter().any(|entry| entry.name == name) {
+                            continue;
+                        }
+                        match libc_wrappers::lstat(upper_entry.path().into_os_string()) {
+                            Ok(stat64) => entries.push(DirectoryEntry {
+                                name,
+                                kind: mode_to_filetype(stat64.st_mode),
+                            }),
+                            Err(e) => error!("lstat({:?}): {}", upper_entry.path(), io::Error::from_raw_os_error(e)),
+                        }
+                    }
+                }
+            },
+            Ok(None) => {},
+            Err(e) => {
+                error!("readdir: {:?}: {:?}", path, e);
+                return Err(e.code());
+            }
+        }
+
         Ok(entries)
     }
 
     fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
         debug!("CALL open: {:?} flags={:#x}", path, flags);
 
-        let real = self.real_path_with_opts(path, true, false)?;
+        let writing = flags as libc::c_int & (libc::O_WRONLY | libc::O_RDWR | libc::O_TRUNC) != 0;
+        let real = if writing {
+            self.writable_path(path)?
+        } else {
+            self.real_path_with_opts(path, true, false)?
+        };
         println!("    Real open path is {}", real.to_str().unwrap());
         match libc_wrappers::open(real, flags as libc::c_int) {
             Ok(fh) => Ok((fh, flags)),
@@ -260,28 +407,28 @@ impl FilesystemMT for PassthroughFS {
         libc_wrappers::close(fh)
     }
 
-    fn read(&self, _req: RequestInfo, path: &Path, fh: u64, offset: u64, size: u32, result: impl FnOnce(Result<&[u8], libc::c_int>)) {
+    fn read(&self, _req: RequestInfo, path: &Path, fh: u64, offset: u64, size: u32, result: impl FnOnce(ResultSlice<'_>) -> CallbackResult) -> CallbackResult {
         debug!("CALL read: {:?} {:#x} @ {:#x}", path, size, offset);
+        if let Err(e) = self.materialize(path, fh) {
+            return result(Err(e));
+        }
         let mut file = unsafe { UnmanagedFile::new(fh) };
 
-        let mut data = Vec::<u8>::with_capacity(size as usize);
-        unsafe { data.set_len(size as usize) };
+        let mut data = vec![0; size as usize];
 
         if let Err(e) = file.seek(SeekFrom::Start(offset)) {
             error!("seek({:?}, {}): {}", path, offset, e);
-            result(Err(e.raw_os_error().unwrap()));
-            return;
+            return result(Err(e.raw_os_error().unwrap()));
         }
         match file.read(&mut data) {
             Ok(n) => { data.truncate(n); },
             Err(e) => {
                 error!("read {:?}, {:#x} @ {:#x}: {}", path, size, offset, e);
-                result(Err(e.raw_os_error().unwrap()));
-                return;
+                return result(Err(e.raw_os_error().unwrap()));
             }
         }
 
-        result(Ok(&data));
+        result(Ok(&data))
     }
 
     fn write(&self, _req: RequestInfo, path: &Path, fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
@@ -337,7 +484,7 @@ impl FilesystemMT for PassthroughFS {
         let result = if let Some(fh) = fh {
             unsafe { libc::fchmod(fh as libc::c_int, mode as libc::mode_t) }
         } else {
-            let real = self.real_path_with_opts(path, true, false)?;
+            let real = self.writable_path(path)?;
             unsafe {
                 let path_c = CString::from_vec_unchecked(real.into_vec());
                 libc::chmod(path_c.as_ptr(), mode as libc::mode_t)
@@ -354,14 +501,14 @@ impl FilesystemMT for PassthroughFS {
     }
 
     fn chown(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, uid: Option<u32>, gid: Option<u32>) -> ResultEmpty {
-        let uid = uid.unwrap_or(::std::u32::MAX);   // docs say "-1", but uid_t is unsigned
-        let gid = gid.unwrap_or(::std::u32::MAX);   // ditto for gid_t
+        let uid = uid.unwrap_or(u32::MAX);   // docs say "-1", but uid_t is unsigned
+        let gid = gid.unwrap_or(u32::MAX);   // ditto for gid_t
         debug!("CALL chmod: {:?} to {}:{}", path, uid, gid);
 
         let result = if let Some(fd) = fh {
             unsafe { libc::fchown(fd as libc::c_int, uid, gid) }
         } else {
-            let real = self.real_path_with_opts(path, true, false)?;
+            let real = self.writable_path(path)?;
             unsafe {
                 let path_c = CString::from_vec_unchecked(real.into_vec());
                 libc::chown(path_c.as_ptr(), uid, gid)
@@ -383,7 +530,7 @@ impl FilesystemMT for PassthroughFS {
         let result = if let Some(fd) = fh {
             unsafe { libc::ftruncate64(fd as libc::c_int, size as i64) }
         } else {
-            let real = self.real_path_with_opts(path, true, false)?;
+            let real = self.writable_path(path)?;
             unsafe {
                 let path_c = CString::from_vec_unchecked(real.into_vec());
                 libc::truncate64(path_c.as_ptr(), size as i64)
@@ -422,7 +569,7 @@ impl FilesystemMT for PassthroughFS {
         let result = if let Some(fd) = fh {
             unsafe { libc::futimens(fd as libc::c_int, &times as *const libc::timespec) }
         } else {
-            let real = self.real_path(path)?;
+            let real = self.writable_path(path)?;
             unsafe {
                 let path_c = CString::from_vec_unchecked(real.into_vec());
                 libc::utimensat(libc::AT_FDCWD, path_c.as_ptr(), &times as *const libc::timespec, libc::AT_SYMLINK_NOFOLLOW)
@@ -484,9 +631,7 @@ impl FilesystemMT for PassthroughFS {
     fn mknod(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32, rdev: u32) -> ResultEntry {
         debug!("CALL mknod: {:?}/{:?} (mode={:#o}, rdev={})", parent_path, name, mode, rdev);
 
-        let mut real = self.real_path(parent_path)?;
-        real.push("/");
-        real.push(name);
+        let real = self.new_path(parent_path, name, false, true)?;
         let result = unsafe {
             let path_c = CString::from_vec_unchecked(real.as_os_str().as_bytes().to_vec());
             libc::mknod(path_c.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t)
@@ -497,7 +642,7 @@ impl FilesystemMT for PassthroughFS {
             error!("mknod({:?}, {}, {}): {}", real, mode, rdev, e);
             Err(e.raw_os_error().unwrap())
         } else {
-            match libc_wrappers::lstat(real.to_os_string()) {
+            match libc_wrappers::lstat(real.clone().into_os_string()) {
                 Ok(attr) => Ok((TTL, stat_to_fuse(attr))),
                 Err(e) => Err(e),   // if this happens, yikes
             }
@@ -507,7 +652,7 @@ impl FilesystemMT for PassthroughFS {
     fn mkdir(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32) -> ResultEntry {
         debug!("CALL mkdir {:?}/{:?} (mode={:#o})", parent_path, name, mode);
 
-        let real = PathBuf::from(self.real_path(parent_path)?).join(name);
+        let real = self.new_path(parent_path, name, false, true)?;
         let result = unsafe {
             let path_c = CString::from_vec_unchecked(real.as_os_str().as_bytes().to_vec());
             libc::mkdir(path_c.as_ptr(), mode as libc::mode_t)
@@ -531,6 +676,9 @@ impl FilesystemMT for PassthroughFS {
     fn unlink(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr) -> ResultEmpty {
         debug!("CALL unlink {:?}/{:?}", parent_path, name);
 
+        if self.remove(parent_path, name, false)? {
+            return Ok(());
+        }
         let real = PathBuf::from(self.real_path_with_opts(parent_path, false, true)?).join(name);
         fs::remove_file(&real)
             .map_err(|ioerr| {
@@ -542,6 +690,9 @@ impl FilesystemMT for PassthroughFS {
     fn rmdir(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr) -> ResultEmpty {
         debug!("CALL rmdir: {:?}/{:?}", parent_path, name);
 
+        if self.remove(parent_path, name, true)? {
+            return Ok(());
+        }
         let real = PathBuf::from(self.real_path(parent_path)?).join(name);
         fs::remove_dir(&real)
             .map_err(|ioerr| {
@@ -553,7 +704,7 @@ impl FilesystemMT for PassthroughFS {
     fn symlink(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, target: &Path) -> ResultEntry {
         debug!("CALL symlink: {:?}/{:?} -> {:?}", parent_path, name, target);
 
-        let real = PathBuf::from(self.real_path_with_opts(parent_path, false, false)?).join(name);
+        let real = self.new_path(parent_path, name, false, false)?;
         match ::std::os::unix::fs::symlink(target, &real) {
             Ok(()) => {
                 match libc_wrappers::lstat(real.clone().into_os_string()) {
@@ -571,10 +722,19 @@ impl FilesystemMT for PassthroughFS {
         }
     }
 
-    // TODO: After rename if you try to fetch the original paths contents what happens?
     fn rename(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, newparent_path: &Path, newname: &OsStr) -> ResultEmpty {
         debug!("CALL rename: {:?}/{:?} -> {:?}/{:?}", parent_path, name, newparent_path, newname);
 
+        let from = parent_path.join(name);
+        let to = newparent_path.join(newname);
+        match self.git.rename(from.strip_prefix("/").unwrap().to_str().unwrap(), to.strip_prefix("/").unwrap().to_str().unwrap()) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {},
+            Err(e) => {
+                error!("rename({:?}, {:?}): {:?}", from, to, e);
+                return Err(e.code());
+            }
+        }
         let real = PathBuf::from(self.real_path(parent_path)?).join(name);
         let newreal = PathBuf::from(self.real_path(newparent_path)?).join(newname);
         fs::rename(&real, &newreal)
@@ -587,8 +747,9 @@ impl FilesystemMT for PassthroughFS {
     fn link(&self, _req: RequestInfo, path: &Path, newparent: &Path, newname: &OsStr) -> ResultEntry {
         debug!("CALL link: {:?} -> {:?}/{:?}", path, newparent, newname);
 
-        let real = self.real_path_with_opts(path, false, false)?;
-        let newreal = PathBuf::from(self.real_path_with_opts(newparent, false, false)?).join(newname);
+        // A file of the cache must not be linked into the overlay, where it could be modified.
+        let real = self.writable_path(path)?;
+        let newreal = self.new_path(newparent, newname, false, false)?;
         match fs::hard_link(&real, &newreal) {
             Ok(()) => {
                 match libc_wrappers::lstat(real.clone()) {
@@ -609,7 +770,7 @@ impl FilesystemMT for PassthroughFS {
     fn create(&self, _req: RequestInfo, parent: &Path, name: &OsStr, mode: u32, flags: u32) -> ResultCreate {
         debug!("CALL create: {:?}/{:?} (mode={:#o}, flags={:#x})", parent, name, mode, flags);
 
-        let real = PathBuf::from(self.real_path_with_opts(parent, true, false)?).join(name);
+        let real = self.new_path(parent, name, true, false)?;
         let fd = unsafe {
             let real_c = CString::from_vec_unchecked(real.clone().into_os_string().into_vec());
             libc::open(real_c.as_ptr(), flags as i32 | libc::O_CREAT | libc::O_EXCL, mode)
@@ -641,8 +802,7 @@ impl FilesystemMT for PassthroughFS {
         let real = self.real_path(path)?;
 
         if size > 0 {
-            let mut data = Vec::<u8>::with_capacity(size as usize);
-            unsafe { data.set_len(size as usize) };
+            let mut data = vec![0; size as usize];
             let nread = libc_wrappers::llistxattr(real, data.as_mut_slice())?;
             data.truncate(nread);
             Ok(Xattr::Data(data))
@@ -658,8 +818,7 @@ impl FilesystemMT for PassthroughFS {
         let real = self.real_path_with_opts(path, true, true)?;
 
         if size > 0 {
-            let mut data = Vec::<u8>::with_capacity(size as usize);
-            unsafe { data.set_len(size as usize) };
+            let mut data = vec![0; size as usize];
             let nread = libc_wrappers::lgetxattr(real, name.to_owned(), data.as_mut_slice())?;
             data.truncate(nread);
             Ok(Xattr::Data(data))
@@ -721,7 +880,7 @@ impl Drop for UnmanagedFile {
     fn drop(&mut self) {
         // Release control of the file descriptor so it is not closed.
         let file = self.inner.take().unwrap();
-        file.into_raw_fd();
+        let _ = file.into_raw_fd();
     }
 }
 
@@ -1,164 +1,678 @@
 extern crate tempdir;
 
-// TODO: Uncomment this when adding support for non-Github repos.
-//use git2::build::{RepoBuilder};
+use git2::{ObjectType, Oid};
 use std::path::{Path};
-use std::process::Command;
-use crate::github::{GithubFS};
+use crate::blob_store::{BlobStore};
+use crate::git_dir;
+use crate::host::{split_repo_name, HostFS, Revision, HISTORY_DIR, REFS_DIR};
+use crate::index::{Index};
+use crate::journal;
+use crate::overlay::{self, Overlay, CHANGES_DIR};
+use crate::prefetch::{PrefetchDir, Prefetcher};
+use crate::provider::{RefKind, RemoteProvider};
+use crate::repo_id::{RepoId};
+use crate::retry::{RetryPolicy};
+use crate::smart_http::{SmartHttpFS};
 use chrono::{DateTime, Utc};
 use std::fs;
 use crate::error::{Result, GitFSError};
 use crate::libc_extras::libc;
-use std::collections::{HashSet};
-use walkdir::WalkDir;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::net::ToSocketAddrs;
+use std::os::unix::fs::PermissionsExt;
+use std::time::{Duration, Instant};
 
+// The file at the root of the mount which shows the remaining API quota of each host.
+const STATUS_FILE: &str = ".status";
+
+// The directory in the state directory which is shown for the directories of hosts which have
+// not answered yet.
+const EMPTY_DIR: &str = "empty";
+
+// How long the result of looking up a host name is kept.
+const HOST_LOOKUP_SECONDS: u64 = 600;
+
+// The state of the filesystem. It is shared by all of the FUSE threads, so each host does its own
+// locking.
 pub struct GitFS {
-    github: GithubFS,
-    timestamp: DateTime<Utc>,
-    // The set of all git URLs which have been cloned using full_clone.
-    fully_cloned_paths: HashSet<String>,
+    // Maps a host name (e.g. github.com) to the filesystem for that host.
+    hosts: RwLock<HashMap<String, Arc<HostFS>>>,
+    // Maps the path of an index to the index. Each index is only opened once, as it is also
+    // updated when the files of a host which is not registered are evicted.
+    indexes: Arc<Mutex<HashMap<String, Arc<Mutex<Index>>>>>,
+    // The time at which every repo is shown, or None to show the current head of each repo.
+    timestamp: Option<DateTime<Utc>>,
     cache_dir: String,
+    // Holds the repos, index and overlay. Each timestamp has its own, as its repos show other
+    // commits, while the file contents, API responses and git objects are shared.
+    state_dir: String,
+    blobs: BlobStore,
+    // Holds the changes which were made to the repos.
+    overlay: Overlay,
+    // None if directories are not prefetched.
+    prefetcher: Option<Arc<Prefetcher>>,
+    // Used by the providers which are registered for plain git servers.
+    retry: RetryPolicy,
+    // The git servers which are reached over plain HTTP instead of HTTPS.
+    http_hosts: HashSet<String>,
+    // Maps a name which was looked up in the root of the filesystem to whether it is a host, and
+    // when it was looked up.
+    host_lookups: Mutex<HashMap<String, (bool, Instant)>>,
+    // The objects which git looked up in .git/objects and which the remote did not have, as
+    // <host>/<owner>/<repo>:<sha>. git looks up every object that it is about to write, which
+    // the remote never has.
+    missing_objects: Mutex<HashSet<String>>,
 }
 
 impl GitFS {
     pub fn new() -> GitFS {
         GitFS{
-            github: GithubFS::new(),
-            timestamp: Utc::now(),
-            fully_cloned_paths: HashSet::new(),
+            hosts: RwLock::new(HashMap::new()),
+            indexes: Arc::new(Mutex::new(HashMap::new())),
+            timestamp: None,
             // This will be filled in later by set_cache_dir.
             cache_dir: "/dev/null".to_string(),
+            state_dir: "/dev/null".to_string(),
+            blobs: BlobStore::new("/dev/null"),
+            overlay: Overlay::new("/dev/null"),
+            prefetcher: None,
+            retry: RetryPolicy::default(),
+            http_hosts: HashSet::new(),
+            host_lookups: Mutex::new(HashMap::new()),
+            missing_objects: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Makes the repos of a hosting provider available under <mountpoint>/<host>. What was cached
+    // for the host before a restart is loaded from its index.
+    pub fn register_provider(&self, host: &str, provider: Box<dyn RemoteProvider>) -> Result<()> {
+        let mut hosts = self.hosts.write().unwrap();
+        // Another thread may have registered the host first.
+        if hosts.contains_key(host) {
+            return Ok(());
         }
+        // The directory of the host is shown even before anything in it is accessed.
+        fs::create_dir_all(format!("{}/repos/{}", self.state_dir, host))?;
+        let index = self.open_index(&self.state_dir, host)?;
+        hosts.insert(host.to_string(), Arc::new(HostFS::new(host, provider, index, self.blobs.clone())));
+        Ok(())
+    }
+
+    fn host(&self, host: &str) -> Option<Arc<HostFS>> {
+        self.hosts.read().unwrap().get(host).cloned()
     }
 
-    pub fn set_token(&mut self, token: String) {
-        self.github.token = token;
+    // Returns the index of a host in a state directory, loading it the first time.
+    fn open_index(&self, state_dir: &str, host: &str) -> Result<Arc<Mutex<Index>>> {
+        open_index(&self.indexes, state_dir, host)
     }
 
     pub fn set_cache_dir(&mut self, cache_dir: String) {
+        self.blobs = BlobStore::new(&cache_dir);
         self.cache_dir = cache_dir;
+        self.set_state_dir();
+    }
+
+    fn set_state_dir(&mut self) {
+        self.state_dir = match self.timestamp {
+            Some(timestamp) => format!("{}/at/{}", self.cache_dir, timestamp.format("%Y-%m-%dT%H:%M:%SZ")),
+            None => self.cache_dir.clone(),
+        };
+        self.overlay = Overlay::new(&self.state_dir);
     }
 
-    pub fn clone_if_not_exist(&mut self, repo_path: String, ignore_base: bool, is_stat: bool) -> Result<String> {
-        let cache_dir = self.cache_dir.clone();
+    // Returns the repo and the path in it if repo_path is in the working tree of a repo, which is
+    // where changes go to the overlay. The metadata directories of the repo are not part of it,
+    // and neither is the repo directory itself unless include_root is set.
+    fn worktree_path(&self, repo_path: &str, include_root: bool) -> Option<(RepoId, String)> {
         let parts: Vec<&str> = repo_path.split("/").collect();
-        println!("repo_path: {}, parts: {:?}", repo_path, parts);
-        // For now we only support github, so all other domains should fail.
-        if parts[0] != "github.com" && parts[0] != "" {
-            return Err(GitFSError::new("Not Found", libc::ENOENT))
+        let host_fs = self.host(parts[0])?;
+        let i = host_fs.provider().repo_index(&parts[1..])? + 1;
+        let repo = host_fs.repo_id(&parts[1..i].join("/"), parts.get(i)?);
+        match parts.get(i + 1) {
+            None if include_root => Some((repo, String::new())),
+            None => None,
+            Some(name) if host_fs.is_metadata_dir(&repo, name) => None,
+            Some(_) => Some((repo, parts[i + 1..].join("/"))),
         }
-        if parts.len() == 1 {
-            if parts[0] != "" {
-                return Ok(format!("{}/repos/github.com", cache_dir));
-            } else {
-                return Ok(format!("{}/repos", cache_dir));
-            }
-        }
-        if parts.len() == 2 {
-            match parts[1] {
-                // These are all common metadata files/directories. Do not try to look them up as
-                // users for performance reasons and because some of these resolve to valid Github 
-                // users.
-                // TODO: There are many more of these which can be filtered.
-                "HEAD"|".git"|"BUILD"|"WORKSPACE"|".idea" => {
+    }
+
+    // Returns the real path of a file which is about to be modified. A file of a repo is copied to
+    // its overlay first, so that the cache is not modified. Other files may be linked to a blob
+    // which is shared with other repos, so they get their own copy.
+    pub fn writable_path(&self, repo_path: String) -> Result<String> {
+        let worktree = self.worktree_path(&repo_path, false);
+        let real_path = self.clone_if_not_exist(repo_path, true, false)?;
+        match worktree {
+            // Only the entries of a directory are changed, which are in the overlay themselves.
+            Some((repo, path)) if !Path::new(&real_path).is_dir() => self.overlay.copy_up(&repo, &path, &real_path),
+            _ => {
+                self.blobs.unshare(Path::new(&real_path))?;
+                Ok(real_path)
+            },
+        }
+    }
+
+    // Returns the real path at which a new file or directory of a repo is created, or None if
+    // repo_path is not in the working tree of a repo.
+    pub fn create_path(&self, repo_path: &str) -> Result<Option<String>> {
+        match self.worktree_path(repo_path, false) {
+            Some((repo, path)) => Ok(Some(self.overlay.create_path(&repo, &path)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Removes a file or an empty directory of a repo. Returns false if repo_path is not in the
+    // working tree of a repo.
+    pub fn remove(&self, repo_path: &str, is_dir: bool) -> Result<bool> {
+        let (repo, path) = match self.worktree_path(repo_path, false) {
+            Some(worktree) => worktree,
+            None => return Ok(false),
+        };
+        let lower_path = format!("{}/repos/{}", self.state_dir, repo_path);
+        // The directory has to be listed to know whether it is empty.
+        if is_dir && Path::new(&lower_path).is_dir() && !self.overlay.is_deleted(&repo, &path)? {
+            self.clone_path(repo_path.to_string(), false, false)?;
+        }
+        self.overlay.remove(&repo, &path, &lower_path, is_dir)?;
+        Ok(true)
+    }
+
+    // Renames a file of a repo. Returns false if neither path is in the working tree of a repo.
+    pub fn rename(&self, from: &str, to: &str) -> Result<bool> {
+        match (self.worktree_path(from, false), self.worktree_path(to, false)) {
+            (None, None) => Ok(false),
+            (Some((repo, path)), Some((to_repo, to_path))) if repo == to_repo => {
+                let lower_path = format!("{}/repos/{}", self.state_dir, from);
+                let in_lower = !self.overlay.is_deleted(&repo, &path)? && fs::symlink_metadata(&lower_path).is_ok();
+                // A directory of the cache would have to be copied with all of its files, so
+                // this is left to the caller, as mv does when renaming across filesystems.
+                if in_lower && Path::new(&lower_path).is_dir() {
+                    return Err(GitFSError::new("Cross-device link", libc::EXDEV));
+                }
+                let upper_path = match in_lower {
+                    true => self.writable_path(from.to_string())?,
+                    false => self.overlay.upper_path(&repo, &path),
+                };
+                fs::rename(&upper_path, self.overlay.create_path(&to_repo, &to_path)?)?;
+                if in_lower {
+                    self.overlay.mark_deleted(&repo, &path)?;
+                }
+                Ok(true)
+            },
+            // Files can not be moved between repos, or in or out of the working tree of a repo.
+            _ => Err(GitFSError::new("Cross-device link", libc::EXDEV)),
+        }
+    }
+
+    // Returns the directory of the overlay which holds the new entries of a directory of a repo,
+    // and the names of its deleted entries.
+    pub fn overlay_entries(&self, repo_path: &str) -> Result<Option<(String, HashSet<String>)>> {
+        match self.worktree_path(repo_path, true) {
+            Some((repo, dir)) => Ok(Some((self.overlay.upper_path(&repo, &dir), self.overlay.deleted_names(&repo, &dir)?))),
+            None => Ok(None),
+        }
+    }
+
+    // Shows every repo as it was at timestamp instead of when the filesystem was mounted.
+    pub fn set_timestamp(&mut self, timestamp: DateTime<Utc>) {
+        self.timestamp = Some(timestamp);
+        self.set_state_dir();
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    // Reaches the git servers with the given host names (which may include a port) over plain
+    // HTTP, e.g. for a local test server.
+    pub fn set_http_hosts(&mut self, hosts: Vec<String>) {
+        self.http_hosts = hosts.into_iter().collect();
+    }
+
+    // Only serves what is already in the cache, without making any requests.
+    pub fn set_offline(&self, offline: bool) {
+        self.retry.network.set_offline(offline);
+    }
+
+    // Limits the total size of the file contents in the cache to limit bytes.
+    pub fn set_cache_limit(&self, limit: u64) -> Result<()> {
+        let indexes = self.indexes.clone();
+        self.blobs.set_limit(limit, move |link| {
+            // The file may be in the repos of another timestamp, or of a host which has not been
+            // accessed since the filesystem was started.
+            let index = open_index(&indexes, link.state_dir().ok_or(GitFSError::NoneError)?, &link.repo.host)?;
+            let mut index = index.lock().unwrap();
+            index.mark_evicted(&link.repo, &link.path)
+        })
+    }
+
+    // Lists depth levels of subdirectories in the background whenever a directory is listed,
+    // fetching the files of up to max_blob_size bytes in them.
+    pub fn set_prefetch(&mut self, depth: usize, max_blob_size: u64) {
+        self.prefetcher = Some(Prefetcher::new(depth, max_blob_size));
+    }
+
+    // Starts prefetching below a directory which was just listed.
+    fn prefetch(&self, host_fs: &Arc<HostFS>, repo: &RepoId, repo_dir: &str, real_repo_path: &str) {
+        if let Some(prefetcher) = &self.prefetcher {
+            prefetcher.prefetch(host_fs.clone(), PrefetchDir{
+                repo: repo.clone(),
+                repo_dir: repo_dir.to_string(),
+                real_repo_path: real_repo_path.to_string(),
+                end_time: self.timestamp,
+            });
+        }
+    }
+
+    pub fn clone_if_not_exist(&self, repo_path: String, ignore_base: bool, is_stat: bool) -> Result<String> {
+        // The changes in the overlay hide what is in the cache.
+        if let Some((repo, path)) = self.worktree_path(&repo_path, false) {
+            if self.overlay.is_deleted(&repo, &path)? {
+                return Err(GitFSError::new("Not Found", libc::ENOENT));
+            }
+            let upper_path = self.overlay.upper_path(&repo, &path);
+            if let Ok(metadata) = fs::symlink_metadata(&upper_path) {
+                // A directory which is also in the cache is read from the cache, and the new
+                // entries in the overlay are added to it when it is listed.
+                if !metadata.is_dir() || !Path::new(&format!("{}/repos/{}", self.state_dir, repo_path)).is_dir() {
+                    return Ok(upper_path);
+                }
+            }
+        }
+        let real_path = match self.clone_path(repo_path.clone(), ignore_base, is_stat) {
+            Ok(real_path) => real_path,
+            // Whatever is already in the cache is served while the host can not be reached. A
+            // placeholder has the size of its file but not its contents, so it can only be looked up.
+            Err(e) if e.code() == libc::EHOSTUNREACH => {
+                let cached_path = format!("{}/repos/{}", self.state_dir, repo_path);
+                if fs::symlink_metadata(&cached_path).is_ok() && (is_stat || !self.is_placeholder(&repo_path)) {
+                    cached_path
+                } else if is_stat {
+                    // Lookups of files which may not exist are common, so they are not errors.
                     return Err(GitFSError::new("Not Found", libc::ENOENT));
-                },
-                _ => {},
+                } else {
+                    return Err(e);
+                }
+            },
+            Err(e) => return Err(e),
+        };
+        if !is_stat {
+            self.blobs.touch(Path::new(&real_path));
+        }
+        Ok(real_path)
+    }
+
+    // Returns true if repo_path is a placeholder whose contents have not been fetched.
+    pub fn is_placeholder(&self, repo_path: &str) -> bool {
+        let parts: Vec<&str> = repo_path.split("/").collect();
+        let host_fs = match self.host(parts[0]) {
+            Some(host_fs) => host_fs,
+            None => return false,
+        };
+        match host_fs.provider().repo_index(&parts[1..]) {
+            Some(i) if parts.len() > i + 2 => {
+                let repo = host_fs.repo_id(&parts[1..i + 1].join("/"), parts[i + 1]);
+                let path = parts[i + 2..].join("/");
+                // The file may have been replaced in the overlay.
+                fs::symlink_metadata(self.overlay.upper_path(&repo, &path)).is_err() && host_fs.is_placeholder(&repo, &path)
+            },
+            _ => false,
+        }
+    }
+
+    // Returns is_git_host(name). The same names are looked up over and over, so the result is
+    // kept for HOST_LOOKUP_SECONDS.
+    fn is_git_host(&self, name: &str) -> bool {
+        if let Some((is_host, time)) = self.host_lookups.lock().unwrap().get(name) {
+            if time.elapsed() < Duration::from_secs(HOST_LOOKUP_SECONDS) {
+                return *is_host;
             }
-            let path = format!("{}/repos/github.com/{}", cache_dir, parts[1]);
-            if !Path::new(&path).exists() {
-                self.github.fill_user_repos(&path, parts[1])?;
+        }
+        let is_host = is_git_host(name);
+        self.host_lookups.lock().unwrap().insert(name.to_string(), (is_host, Instant::now()));
+        is_host
+    }
+
+    // Returns the path of an empty directory, which stands in for a directory of a host which
+    // may not have any repos.
+    fn empty_dir(&self) -> Result<String> {
+        let path = format!("{}/{}", self.state_dir, EMPTY_DIR);
+        fs::create_dir_all(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o555))?;
+        Ok(path)
+    }
+
+    // Writes the status file with the current quotas and returns its path.
+    fn write_status(&self) -> Result<String> {
+        let mut status = String::new();
+        let hosts = self.hosts.read().unwrap();
+        let mut names: Vec<&String> = hosts.keys().collect();
+        names.sort();
+        if self.retry.network.is_offline() {
+            status.push_str("Offline, only the cache is used\n");
+        }
+        for (name, seconds) in self.retry.network.unreachable_hosts() {
+            status.push_str(&format!("{} can not be reached, trying again in {} seconds\n", name, seconds));
+        }
+        for name in names {
+            for limit in hosts[name].provider().rate_limits() {
+                status.push_str(&format!("{} {}: {} of {} requests remaining, resets at {}\n",
+                    name, limit.resource, limit.remaining, limit.limit, limit.reset.format("%Y-%m-%dT%H:%M:%SZ")));
+            }
+        }
+        let path = format!("{}/repos/{}", self.state_dir, STATUS_FILE);
+        fs::create_dir_all(format!("{}/repos", self.state_dir))?;
+        journal::write_atomic(&path, status.as_bytes())?;
+        Ok(path)
+    }
+
+    fn clone_path(&self, repo_path: String, ignore_base: bool, is_stat: bool) -> Result<String> {
+        let state_dir = self.state_dir.clone();
+        let parts: Vec<&str> = repo_path.split("/").collect();
+        println!("repo_path: {}, parts: {:?}", repo_path, parts);
+        if parts[0].is_empty() {
+            return Ok(format!("{}/repos", state_dir));
+        }
+        if parts == [STATUS_FILE] {
+            return self.write_status();
+        }
+        let host = parts[0];
+        if self.host(host).is_none() {
+            // Hosts without their own provider are treated as plain git servers.
+            let remote = self.smart_http(host);
+            let is_cached = !host.starts_with(".") && Path::new(&format!("{}/repos/{}", state_dir, host)).is_dir();
+            if !is_cached {
+                // Looking up a host needs the network, so only the hosts in the cache are shown
+                // while offline.
+                if self.retry.network.is_offline() || !(self.http_hosts.contains(host) || self.is_git_host(host)) {
+                    return Err(GitFSError::new("Not Found", libc::ENOENT));
+                }
+                // The host is only added to the cache once its server has answered for one of
+                // its repos. Until then, it and the directories above the repo are empty.
+                let i = match remote.repo_index(&parts[1..]) {
+                    Some(i) => i + 1,
+                    None => return self.empty_dir(),
+                };
+                remote.check_repo(&parts[1..i].join("/"), split_repo_name(parts[i]).0)?;
             }
-            return Ok(path);
+            self.register_provider(host, Box::new(remote))?;
         }
-        if parts.len() < 3 {
-            return Ok("/".to_string())
+        let host_fs = self.host(host).ok_or(GitFSError::NoneError)?;
+        if parts.len() == 1 {
+            return Ok(format!("{}/repos/{}", state_dir, host));
         }
+        let repo_index = match host_fs.provider().repo_index(&parts[1..]) {
+            Some(i) => i + 1,
+            None => {
+                match parts[parts.len() - 1] {
+                    // These are all common metadata files/directories. Do not try to look them up as
+                    // users for performance reasons and because some of these resolve to valid Github
+                    // users.
+                    // TODO: There are many more of these which can be filtered.
+                    "HEAD"|".git"|"BUILD"|"WORKSPACE"|".idea" => {
+                        return Err(GitFSError::new("Not Found", libc::ENOENT));
+                    },
+                    _ => {},
+                }
+                let user = parts[1..].join("/");
+                let path = format!("{}/repos/{}/{}", state_dir, host, user);
+                host_fs.fill_user_repos(&path, &user)?;
+                // Any name may be a directory above repos which have not been found yet, so it is
+                // shown as empty without being added to the cache.
+                if !host_fs.provider().can_list_repos() && !Path::new(&path).is_dir() {
+                    return self.empty_dir();
+                }
+                return Ok(path);
+            },
+        };
+        let user = parts[1..repo_index].join("/");
+        // This is the directory name of the repo, which may be <repo>@<ref>.
+        let repo = parts[repo_index];
+        let repo_id = host_fs.repo_id(&user, repo);
 
         // real_repo_path is the location of the repo in the real local filesystem.
-        let real_repo_path = format!("{}/repos/github.com/{}/{}", cache_dir, parts[1], parts[2]);
-        let path_in_repo = parts[3..].join("/");
-        let real_file_path = format!("{}/repos/github.com/{}", cache_dir, parts[1..].join("/").as_str());
+        let real_repo_path = format!("{}/repos/{}/{}", state_dir, host, parts[1..repo_index + 1].join("/"));
+        let path_in_repo = parts[repo_index + 1..].join("/");
+        let real_file_path = format!("{}/repos/{}/{}", state_dir, host, parts[1..].join("/").as_str());
 
         // If this is only the full repo path and the base path is being ignored, then do not clone the
         // repo.
-        if ignore_base && parts.len() == 3 {
-            let path = format!("{}/repos/github.com/{}", cache_dir, parts[1]);
-            self.github.fill_user_repos(&path, parts[1])?;
+        if ignore_base && parts.len() == repo_index + 1 {
+            let path = format!("{}/repos/{}/{}", state_dir, host, user);
+            host_fs.fill_user_repos(&path, &user)?;
             if Path::new(&real_repo_path).exists() {
                 return Ok(real_repo_path);
             }
-            println!("DOES NOT EXIST {}", &real_repo_path);
-            return Err(GitFSError::new("Not Found", libc::ENOENT));
+            if host_fs.provider().can_list_repos() && repo == repo_id.name {
+                println!("DOES NOT EXIST {}", &real_repo_path);
+                return Err(GitFSError::new("Not Found", libc::ENOENT));
+            }
+            // The repo (or ref) is not in any listing, so the only way to know if it exists is to
+            // list it.
+            if let Err(e) = host_fs.clone_dir("", &real_repo_path, &repo_id, self.timestamp) {
+                fs::remove_dir_all(&real_repo_path)?;
+                // The directories above a repo which was not found are not left behind either.
+                if !host_fs.provider().can_list_repos() {
+                    let host_path = format!("{}/repos/{}", state_dir, host);
+                    let mut dir = Path::new(&real_repo_path).parent();
+                    while let Some(parent) = dir.filter(|parent| *parent != Path::new(&host_path)) {
+                        if fs::remove_dir(parent).is_err() {
+                            break;
+                        }
+                        dir = parent.parent();
+                    }
+                }
+                return Err(e);
+            }
+            self.prefetch(&host_fs, &repo_id, "", &real_repo_path);
+            return Ok(real_repo_path);
         }
-        let url = "https://".to_owned() + parts[0..3].join("/").as_str() + ".git";
+        // A repo directory which follows a branch shows where the branch is now.
+        host_fs.refresh(&repo_id, &real_repo_path, self.timestamp)?;
+        let url = host_fs.provider().clone_url(&user, &repo_id.name);
         println!("Final Repo URL: {:?}", url);
 
+        // A directory of the repo hides the metadata directory with the same name, so the root of
+        // the repo has to be listed to know which one a path is in.
+        let metadata_dir = match parts.get(repo_index + 1) {
+            Some(&name) if name == REFS_DIR || name == HISTORY_DIR || name == CHANGES_DIR => {
+                if !host_fs.is_structure_cloned(&repo_id, "") {
+                    host_fs.clone_dir("", &real_repo_path, &repo_id, self.timestamp)?;
+                }
+                Some(name).filter(|name| host_fs.is_metadata_dir(&repo_id, name))
+            },
+            _ => None,
+        };
+
         // If the path is in the .git directory, clone if needed then return the path to the real
         // file.
-        if parts.len() > 3 && parts[3] == ".git" {
+        if parts.len() > repo_index + 1 && parts[repo_index + 1] == ".git" {
             // If it is simply a stat of the .git directory, just return the path to the empty
             // file.
-            if is_stat && parts.len() == 4 {
+            if is_stat && parts.len() == repo_index + 2 {
                 fs::create_dir_all(&real_file_path)?;
                 return Ok(real_file_path);
             }
-            self.full_clone(parts[1], parts[2], &url, &cache_dir, &real_repo_path)?;
+            self.create_git_dir(&host_fs, &repo_id, &url, &real_repo_path, self.timestamp)?;
+            // git looks for each object that it needs at .git/objects/<xx>/<rest of the sha>. It
+            // also looks for the objects that it is about to write, so a failure is not an error.
+            if let Some(sha) = git_dir::loose_object_sha(&parts[repo_index + 2..]) {
+                if !Path::new(&real_file_path).exists() {
+                    if let Err(e) = self.fetch_git_object(&host_fs, &repo_id, &real_repo_path, &sha) {
+                        println!("Unable to fetch object {} ({})", sha, e);
+                    }
+                }
+            }
+            return Ok(real_file_path);
+        }
+
+        // The refs directory links to the <repo>@<ref> directory of every branch and tag. The links
+        // for a kind of ref are only created when its directory is accessed.
+        if metadata_dir == Some(REFS_DIR) {
+            let refs_dir = format!("{}/{}", real_repo_path, REFS_DIR);
+            fs::create_dir_all(format!("{}/branches", refs_dir))?;
+            fs::create_dir_all(format!("{}/tags", refs_dir))?;
+            match parts.get(repo_index + 2) {
+                Some(&"branches") => host_fs.fill_refs(&refs_dir, &repo_id, RefKind::Branch)?,
+                Some(&"tags") => host_fs.fill_refs(&refs_dir, &repo_id, RefKind::Tag)?,
+                _ => {},
+            }
+            return Ok(real_file_path);
+        }
+
+        // The changes directory lists what was changed in the overlay. The list is written again
+        // whenever it is accessed.
+        if metadata_dir == Some(CHANGES_DIR) {
+            fs::create_dir_all(format!("{}/{}", real_repo_path, CHANGES_DIR))?;
+            if parts.len() == repo_index + 3 && parts[repo_index + 2] == overlay::STATUS_FILE {
+                self.overlay.write_status(&repo_id, &real_repo_path, &real_file_path, |path| host_fs.blob_sha(&repo_id, path))?;
+            }
+            return Ok(real_file_path);
+        }
+
+        // The history directory links to the <repo>@<sha> directory of recent commits. A stat of
+        // the directory itself does not need the commits.
+        if metadata_dir == Some(HISTORY_DIR) {
+            let history_dir = format!("{}/{}", real_repo_path, HISTORY_DIR);
+            fs::create_dir_all(format!("{}/by-date", history_dir))?;
+            fs::create_dir_all(format!("{}/by-message", history_dir))?;
+            if !is_stat || parts.len() > repo_index + 2 {
+                host_fs.fill_history(&history_dir, &repo_id, self.timestamp)?;
+            }
             return Ok(real_file_path);
         }
 
         // If all we need is metadata about the file/directory, then it is sufficient to just clone the parent directory.
         if is_stat {
-            let repo_parent = Path::new(&path_in_repo).parent().unwrap_or(Path::new("/")).to_str()?;
-            if self.github.is_structure_cloned(parts[2], repo_parent) || self.github.is_structure_cloned(parts[2], &path_in_repo) {
-                return Ok(real_file_path)
+            let repo_parent = Path::new(&path_in_repo).parent().unwrap_or(Path::new("/")).to_str().ok_or(GitFSError::NoneError)?;
+            if !host_fs.is_structure_cloned(&repo_id, repo_parent) && !host_fs.is_structure_cloned(&repo_id, &path_in_repo) {
+                host_fs.clone_dir(repo_parent, &real_repo_path, &repo_id, self.timestamp)?;
+                self.prefetch(&host_fs, &repo_id, repo_parent, &real_repo_path);
+            }
+            // A placeholder without a size has to be downloaded before it can be stat'ed.
+            if host_fs.is_unsized(&repo_id, &path_in_repo) {
+                host_fs.clone_dir(&path_in_repo, &real_repo_path, &repo_id, self.timestamp)?;
             }
-            self.github.clone_dir(repo_parent, &real_repo_path, parts[1], parts[2], self.timestamp)?;
             return Ok(real_file_path)
         }
-        if self.github.is_structure_cloned(parts[2], &path_in_repo) {
+        if host_fs.is_structure_cloned(&repo_id, &path_in_repo) {
             return Ok(real_file_path)
         }
-        self.github.clone_dir(&path_in_repo, &real_repo_path, parts[1], parts[2], self.timestamp)?;
+        host_fs.clone_dir(&path_in_repo, &real_repo_path, &repo_id, self.timestamp)?;
+        self.prefetch(&host_fs, &repo_id, &path_in_repo, &real_repo_path);
         Ok(real_file_path)
     }
 
-    fn full_clone(&mut self, user: &str, repo: &str, url: &str, cache_dir: &str, repo_path: &str) -> Result<()> {
-        let repo_clone_dir = format!("{}/tmp_repos/{}/{}", cache_dir, user, repo);
-        if self.fully_cloned_paths.contains(url) {
+    fn smart_http(&self, host: &str) -> SmartHttpFS {
+        let mut remote = SmartHttpFS::new(host, &self.cache_dir, self.retry.clone());
+        remote.set_plain_http(self.http_hosts.contains(host));
+        remote
+    }
+
+    // Synthesizes the .git directory of a repo directory for the commit which the directory
+    // shows. Only the commit and its trees are fetched. Every directory of the repo is listed
+    // first, so that the index can be written with the placeholders of the files.
+    fn create_git_dir(&self, host_fs: &HostFS, repo: &RepoId, url: &str, repo_path: &str, end_time: Option<DateTime<Utc>>) -> Result<()> {
+        host_fs.with_repo_lock(repo, ".git", || {
+            if host_fs.is_structure_cloned(repo, ".git") {
+                return Ok(());
+            }
+            let remote = host_fs.provider().git_remote();
+            let objects = remote.open_repo(&repo.owner, &repo.name)?;
+            let objects_dir = objects.path().join("objects").to_str().ok_or(GitFSError::NoneError)?.to_string();
+            let sha = host_fs.resolve(repo, end_time)?;
+            let mut head_ref = None;
+            if let Some(sha) = &sha {
+                remote.fetch_trees(&repo.owner, &repo.name, sha)?;
+                for dir in git_dir::tree_dirs(&objects, sha)? {
+                    if !host_fs.is_structure_cloned(repo, &dir) {
+                        host_fs.clone_dir(&dir, repo_path, repo, end_time)?;
+                    }
+                }
+                head_ref = self.head_ref(host_fs, repo)?;
+            }
+            // The metadata directories are not part of the repo.
+            let excluded: Vec<&str> = [REFS_DIR, HISTORY_DIR, CHANGES_DIR].iter().cloned()
+                .filter(|name| host_fs.is_metadata_dir(repo, name))
+                .collect();
+            git_dir::create(repo_path, url, &objects_dir, sha.as_deref(), head_ref.as_deref(), &excluded)?;
+            host_fs.mark_as_cloned(repo, ".git")
+        })
+    }
+
+    // Returns the full name of the branch or tag which a <repo>@<ref> directory shows. The
+    // default branch and directories at a point in time or at a sha do not have one.
+    fn head_ref(&self, host_fs: &HostFS, repo: &RepoId) -> Result<Option<String>> {
+        let name = match repo.revision() {
+            Revision{reference: Some(name), end_time: None} => name,
+            _ => return Ok(None),
+        };
+        if host_fs.provider().list_refs(&repo.owner, &repo.name, RefKind::Branch)?.contains(&name) {
+            return Ok(Some(format!("refs/heads/{}", name)));
+        }
+        if host_fs.provider().list_refs(&repo.owner, &repo.name, RefKind::Tag)?.contains(&name) {
+            return Ok(Some(format!("refs/tags/{}", name)));
+        }
+        Ok(None)
+    }
+
+    // Adds an object which git looked up in a repo's .git to the shared object store, where git
+    // looks next. A file of the repo is fetched like any other file, anything else with the git
+    // protocol. If the object can not be found, git fetches it from the remote itself.
+    fn fetch_git_object(&self, host_fs: &HostFS, repo: &RepoId, repo_path: &str, sha: &str) -> Result<()> {
+        let key = format!("{}/{}/{}:{}", repo.host, repo.owner, repo.name, sha);
+        if self.missing_objects.lock().unwrap().contains(&key) {
             return Ok(());
         }
-        let mut child = Command::new("git")
-            .arg("clone")
-            //.arg("--no-checkout")
-            .arg(url)
-            .arg(&repo_clone_dir)
-            .spawn()?;
-        child.wait()?;
-        let tmp_repo_git_dir = format!("{}/.git", &repo_clone_dir);
-        let real_repo_git_dir = format!("{}/.git", repo_path);
-        fs::rename(tmp_repo_git_dir, real_repo_git_dir)?;
-        for entry_result in WalkDir::new(&repo_clone_dir) {
-            let entry = entry_result.unwrap(); // TODO: Do not use unwrap.
-            let entry_path = entry.path();
-            let relative_path = entry_path.strip_prefix(&repo_clone_dir).unwrap_or(entry_path).to_str()?;
-            println!("path = {}, relative_path = {}", entry_path.display(), relative_path);
-            if !self.github.is_structure_cloned(repo, relative_path) {
-                if entry.file_type().is_dir() {
-                    fs::create_dir_all(format!("{}/{}", repo_path, relative_path))?;
-                } else {
-                    fs::create_dir_all(Path::new(&format!("{}/{}", repo_path, relative_path)).parent()?)?;
-                    println!("Copying path = {}, relative_path = {}", entry_path.display(), relative_path);
-                    fs::rename(entry_path, format!("{}/{}", repo_path, relative_path))?;
+        let remote = host_fs.provider().git_remote();
+        let objects = remote.open_repo(&repo.owner, &repo.name)?;
+        let odb = objects.odb()?;
+        let oid = Oid::from_str(sha)?;
+        if odb.exists(oid) {
+            return Ok(());
+        }
+        if let Some(path) = host_fs.path_of(repo, sha) {
+            let real_path = format!("{}/{}", repo_path, path);
+            if Path::new(&real_path).is_file() {
+                if !host_fs.is_structure_cloned(repo, &path) {
+                    host_fs.clone_dir(&path, repo_path, repo, self.timestamp)?;
+                }
+                // The file may have been modified since, in which case it is not the object.
+                if odb.write(ObjectType::Blob, &fs::read(&real_path)?)? == oid {
+                    return Ok(());
                 }
-                // Mark the file as cached.
-                self.github.mark_as_cloned(repo,  relative_path.to_string());
-            } else {
-                println!("NOT copying path = {}, relative_path = {}", entry_path.display(), relative_path);
             }
         }
-        println!("Everything was copied.");
-        self.fully_cloned_paths.insert(url.to_string());
-        return Ok(());
+        if !remote.fetch_any_object(&repo.owner, &repo.name, sha)? {
+            self.missing_objects.lock().unwrap().insert(key);
+        }
+        Ok(())
+    }
+}
+
+// Returns true if name looks like the host name of a git server, which may be followed by a
+// port. Lookups of arbitrary names in the root of the filesystem are common, so the name must
+// also resolve.
+fn is_git_host(name: &str) -> bool {
+    if name.starts_with(".") || !name.contains(".") && !name.contains(":") {
+        return false;
+    }
+    match name.contains(":") {
+        true => name.to_socket_addrs().is_ok(),
+        false => format!("{}:443", name).to_socket_addrs().is_ok(),
+    }
+}
+
+// Returns the index of a host in a state directory from indexes, loading it the first time.
+fn open_index(indexes: &Mutex<HashMap<String, Arc<Mutex<Index>>>>, state_dir: &str, host: &str) -> Result<Arc<Mutex<Index>>> {
+    let path = format!("{}/index/{}", state_dir, host);
+    let mut indexes = indexes.lock().unwrap();
+    if let Some(index) = indexes.get(&path) {
+        return Ok(index.clone());
     }
+    let index = Arc::new(Mutex::new(Index::open(&path)?));
+    indexes.insert(path, index.clone());
+    Ok(index)
 }
@@ -0,0 +1,126 @@
+// Synthesizes the .git directory of a repo directory, so that git commands work on the files
+// which the mount shows without cloning the repo. It only has HEAD, the ref which the directory
+// names and an index. Objects are kept in the shared object store of the repo, which git finds
+// through objects/info/alternates, and are added to it when git looks them up in .git/objects.
+// The repo is also set up as a partial clone (filter=blob:none), so that git can fetch anything
+// that the mount could not from the remote itself.
+//
+// git status compares every file with the index, and reads the files whose size or mtime does
+// not match. To keep it from reading (and so downloading) every file in the repo, the index is
+// written with the stat data of the files in the cache.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use chrono::Utc;
+use git2::{IndexTime, ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use crate::error::{Result};
+
+// Returns the sha of the object if path (relative to .git) is the path of a loose object.
+pub fn loose_object_sha(path: &[&str]) -> Option<String> {
+    match path {
+        ["objects", dir, name] if dir.len() == 2 && name.len() == 38 => {
+            let sha = format!("{}{}", dir, name);
+            if sha.chars().all(|c| c.is_ascii_hexdigit()) {
+                Some(sha)
+            } else {
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+// Returns the path of every directory in the tree of a commit, relative to the root of the repo.
+pub fn tree_dirs(git_repo: &Repository, sha: &str) -> Result<Vec<String>> {
+    let tree = git_repo.find_commit(Oid::from_str(sha)?)?.tree()?;
+    let mut dirs = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |parent, entry| {
+        if entry.kind() == Some(ObjectType::Tree) {
+            dirs.push(format!("{}{}", parent, entry.name().unwrap_or("")));
+        }
+        TreeWalkResult::Ok
+    })?;
+    Ok(dirs)
+}
+
+// Creates repo_path/.git with origin set to url. HEAD is at sha, or unborn if the repo does not
+// have any commits. If head_ref (a full ref name) is given, it is created at sha as well, and HEAD
+// points to it if it is a branch. objects_dir must already hold the commit and its trees. The
+// directories at the root of the repo which are named in excluded are ignored by git.
+pub fn create(repo_path: &str, url: &str, objects_dir: &str, sha: Option<&str>, head_ref: Option<&str>, excluded: &[&str]) -> Result<()> {
+    let git_dir = format!("{}/.git", repo_path);
+    // A stat of .git leaves an empty directory, and an attempt which did not finish may have left
+    // more.
+    if Path::new(&git_dir).exists() {
+        fs::remove_dir_all(&git_dir)?;
+    }
+    Repository::init(repo_path)?;
+    fs::write(format!("{}/objects/info/alternates", git_dir), format!("{}\n", objects_dir))?;
+    let exclude: String = excluded.iter().map(|name| format!("/{}/\n", name)).collect();
+    fs::write(format!("{}/info/exclude", git_dir), exclude)?;
+
+    // The repo is opened again so that it sees the alternate object store.
+    let git_repo = Repository::open(repo_path)?;
+    git_repo.remote("origin", url)?;
+    let mut config = git_repo.config()?;
+    config.set_i32("core.repositoryformatversion", 1)?;
+    config.set_str("extensions.partialClone", "origin")?;
+    config.set_bool("remote.origin.promisor", true)?;
+    config.set_str("remote.origin.partialclonefilter", "blob:none")?;
+    // Inode and device numbers in the mount are not the ones in the cache, so only the size and
+    // mtime of each file are compared with the index.
+    config.set_str("core.checkStat", "minimal")?;
+    config.set_bool("core.trustctime", false)?;
+
+    let sha = match sha {
+        Some(sha) => Oid::from_str(sha)?,
+        None => return Ok(()),
+    };
+    let commit = git_repo.find_commit(sha)?;
+    match head_ref {
+        Some(name) => {
+            git_repo.reference(name, sha, true, "")?;
+            if name.starts_with("refs/heads/") {
+                git_repo.set_head(name)?;
+            } else {
+                git_repo.set_head_detached(sha)?;
+            }
+        },
+        None => git_repo.set_head_detached(sha)?,
+    }
+
+    let mut index = git_repo.index()?;
+    index.read_tree(&commit.tree()?)?;
+    let mut entries = Vec::new();
+    let mut latest_mtime = 0;
+    for mut entry in index.iter() {
+        let path = format!("{}/{}", repo_path, String::from_utf8_lossy(&entry.path));
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            // Submodules are not in the cache. git checks them itself.
+            Err(_) => continue,
+        };
+        entry.mtime = IndexTime::new(metadata.mtime() as i32, metadata.mtime_nsec() as u32);
+        entry.ctime = IndexTime::new(metadata.ctime() as i32, metadata.ctime_nsec() as u32);
+        entry.file_size = metadata.len() as u32;
+        entry.uid = metadata.uid();
+        entry.gid = metadata.gid();
+        latest_mtime = latest_mtime.max(metadata.mtime());
+        entries.push(entry);
+    }
+    for entry in &entries {
+        index.add(entry)?;
+    }
+    // git reads the files which were modified in the same second as the index was written, since
+    // they may have changed after it was written. Waiting for the next second avoids that. Files
+    // whose mtimes are in the future are read by git either way, so they are not waited for.
+    let latest_mtime = latest_mtime.min(Utc::now().timestamp());
+    while Utc::now().timestamp() <= latest_mtime {
+        thread::sleep(Duration::from_millis(100));
+    }
+    index.write()?;
+    Ok(())
+}
@@ -1,234 +1,777 @@
-use std::fs;
-use chrono::{DateTime, Utc};
-use std::ops::{Sub};
-use time::Duration;
+use chrono::{DateTime, TimeZone, Utc};
 use std::fs::File;
-use std::os::unix::fs::OpenOptionsExt;
-use std::io::prelude::*;
-use std::convert::TryInto;
-use std::collections::{HashMap, HashSet};
-use reqwest;
-use std::path::Path;
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LINK, RETRY_AFTER, USER_AGENT};
+use std::collections::HashMap;
 use std::io;
-use std::u32;
+use std::thread;
+use std::time::Duration;
 use crate::error::{GitFSError, Result};
 use crate::libc_extras::libc;
+use crate::provider::{CommitInfo, EntryKind, RateLimit, RefKind, RemoteProvider, Tree, TreeEntry};
+use crate::response_cache::{CachedResponse, ResponseCache};
+use crate::retry::{RetryPolicy};
+use crate::smart_http::{SmartHttpFS};
+use serde_json::json;
+use serde_json::value::Value;
+use std::sync::Mutex;
 
-struct Repo {
-    // Maps a directory to a tree sha.
-    tree: HashMap<String, String>,
-    cloned_structures: HashSet<String>,
-    timestamp_to_sha: Option<(DateTime<Utc>, String)>,
-    zero_files: HashSet<String>,
+// The default location of the Github API.
+pub const GITHUB_API_URL: &str = "https://api.github.com";
+
+// A rate limited request is retried if the limit is lifted within this many seconds. Otherwise
+// the request fails rather than blocking the filesystem.
+const MAX_RATE_LIMIT_WAIT: u64 = 60;
+
+// The number of times a rate limited request is retried.
+const RATE_LIMIT_RETRIES: u32 = 3;
+
+// Files up to this size are returned along with their directory by GraphQL directory listings.
+const INLINE_BLOB_SIZE: u64 = 64 * 1024;
+
+// Lists a tree, or the root tree of a commit, along with the contents of its small text files.
+const TREE_QUERY: &str = "
+query($owner: String!, $name: String!, $oid: GitObjectID!) {
+  repository(owner: $owner, name: $name) {
+    object(oid: $oid) {
+      __typename
+      ... on Commit { tree { ...treeFields } }
+      ... on Tree { ...treeFields }
+    }
+  }
 }
+fragment treeFields on Tree {
+  oid
+  entries {
+    name
+    type
+    mode
+    oid
+    object { ... on Blob { byteSize isBinary isTruncated text } }
+  }
+}";
+
+// Lists one page of the repos which a user or organization owns. Repos which a user only
+// collaborates on are listed under their own owners.
+const REPOS_QUERY: &str = "
+query($login: String!, $cursor: String) {
+  repositoryOwner(login: $login) {
+    repositories(first: 100, after: $cursor, ownerAffiliations: [OWNER]) {
+      nodes { name }
+      pageInfo { hasNextPage endCursor }
+    }
+  }
+}";
 
 pub struct GithubFS {
-    // Maps a repo name to a repo.
-    repos: HashMap<String, Repo>,
-    fetched_users: HashSet<String>,
     pub token: String,
+    // The base URL of the REST API.
+    api_url: String,
+    // The URL of the GraphQL API.
+    graphql_url: String,
+    // Fetches from the site which repos are cloned from.
+    remote: SmartHttpFS,
+    // If true, directories and repos are listed with the GraphQL API instead of the REST API.
+    graphql: bool,
+    // The login of the user who the token belongs to, once it is known.
+    login: Mutex<Option<String>>,
+    // Shared by every request so that connections to the API are reused.
+    client: Client,
+    responses: ResponseCache,
+    // Maps an API (core, graphql, search, ...) to its quota as of the last response.
+    rate_limits: Mutex<HashMap<String, RateLimit>>,
+    retry: RetryPolicy,
 }
 
 impl GithubFS {
-    pub fn new() -> GithubFS {
+    pub fn new(token: String, cache_dir: &str, api_url: &str, graphql: bool, retry: RetryPolicy) -> GithubFS {
+        let api_url = api_url.trim_end_matches('/');
+        // The responses and objects of each server are cached apart.
+        let host = web_host(api_url);
+        let mut remote = SmartHttpFS::new(&host, cache_dir, retry.clone());
+        remote.set_base_url(&web_url(api_url), ".git");
+        if !token.is_empty() {
+            // Github accepts a token as the password for any user name.
+            remote.set_credentials(Some(("x-access-token".to_string(), token.clone())));
+        }
         GithubFS{
-            repos: HashMap::new(),
-            fetched_users: HashSet::new(),
-            token: "".to_string(),
+            token: token,
+            api_url: api_url.to_string(),
+            graphql_url: graphql_url(api_url),
+            remote: remote,
+            graphql: graphql,
+            login: Mutex::new(None),
+            client: retry.client(),
+            responses: ResponseCache::new(&format!("{}/responses/{}", cache_dir, host)),
+            rate_limits: Mutex::new(HashMap::new()),
+            retry: retry,
         }
     }
 
-    fn get_repo_or_create(&mut self, repo_name: &str) -> &mut Repo {
-        self.repos.entry(repo_name.to_string()).or_insert_with(|| Repo{
-            tree: HashMap::new(),
-            cloned_structures: HashSet::new(),
-            timestamp_to_sha: None,
-            zero_files: HashSet::new(),
-        })
+    // Sends the request made by build. If the rate limit has been exceeded, the request is sent
+    // again once it is lifted, as long as that is soon. build is called for each attempt.
+    fn send<F>(&self, build: F) -> Result<Response>
+        where F: Fn() -> RequestBuilder {
+        let mut backoff = 1;
+        for attempt in 0..=RATE_LIMIT_RETRIES {
+            let res = build().send()?;
+            self.update_rate_limit(res.headers());
+            let wait = match rate_limit_wait(&res) {
+                Some(wait) => wait.max(backoff),
+                None => return Ok(res),
+            };
+            if wait > MAX_RATE_LIMIT_WAIT || attempt == RATE_LIMIT_RETRIES {
+                eprintln!("Rate limit exceeded for {}, it is lifted in {} seconds", res.url(), wait);
+                break;
+            }
+            println!("Rate limit exceeded for {}, retrying in {} seconds", res.url(), wait);
+            thread::sleep(Duration::from_secs(wait));
+            backoff *= 2;
+        }
+        Err(GitFSError::new("API rate limit exceeded", libc::EAGAIN))
     }
 
-    // TODO: This will cause issues when multiple users have a repo of the same name.
-    pub fn is_structure_cloned(&self, repo: &str, repo_dir: &str) -> bool {
-        let repo_struct = self.repos.get(repo);
-        if repo_struct.is_none() {
-            return false;
-        }
-        repo_struct.unwrap().cloned_structures.contains(repo_dir)
+    fn update_rate_limit(&self, headers: &HeaderMap) {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let number = |name| header(name).and_then(|value| value.parse::<u64>().ok());
+        let reset = number("x-ratelimit-reset").and_then(|reset| Utc.timestamp_opt(reset as i64, 0).single());
+        let (limit, remaining, reset) = match (number("x-ratelimit-limit"), number("x-ratelimit-remaining"), reset) {
+            (Some(limit), Some(remaining), Some(reset)) => (limit, remaining, reset),
+            // Only API responses have a rate limit.
+            _ => return,
+        };
+        let resource = header("x-ratelimit-resource").unwrap_or("core").to_string();
+        self.rate_limits.lock().unwrap().insert(resource.clone(), RateLimit{
+            resource: resource,
+            limit: limit,
+            remaining: remaining,
+            reset: reset,
+        });
     }
 
-    // Returns an option which indicates if the repo exists.
-    pub fn mark_as_cloned(&mut self, repo: &str, repo_file: String) {
-        self.get_repo_or_create(repo).cloned_structures.insert(repo_file);
+    // Returns the most recent commit made at or before end_time which is reachable from reference,
+    // or None if there is no such commit.
+    fn latest_commit_before(&self, user: &str, repo: &str, reference: Option<&str>, end_time: DateTime<Utc>) -> Result<Option<String>> {
+        // The time is formatted without an offset, as a '+' in the query would be read as a space.
+        let until = end_time.format("%Y-%m-%dT%H:%M:%SZ");
+        let mut endpoint = format!("repos/{}/{}/commits?until={}&per_page=1", user, repo, until);
+        if let Some(reference) = reference {
+            endpoint.push_str(&format!("&sha={}", encode_component(reference)));
+        }
+        let json = self.api_call_request(&endpoint)?;
+        // Commits are sorted from most to least recent.
+        match json.as_array().ok_or(GitFSError::NoneError)?.first() {
+            Some(commit) => Ok(Some(commit["sha"].as_str().ok_or(GitFSError::NoneError)?.to_string())),
+            None => Ok(None),
+        }
     }
 
-    // Clones a specific directory inside of a repo, saving the empty files to the cache.
-    pub fn clone_dir(&mut self, repo_dir: &str, cache_dir: &str, user: &str, repo: &str, end_time: DateTime<Utc>) -> Result<()> {
-        // TODO: Do not create dirs that do not exist.
-        fs::create_dir_all(cache_dir)?;
-        match self.get_repo_or_create(repo).timestamp_to_sha.clone() {
-            Some((_timestamp, sha)) => {
-                println!("Already has timestamp");
-                return self.create_fake_listing(user, repo, &sha, repo_dir, cache_dir);
+    // Lists every repo of a user or organization, including the private ones which the token can
+    // see.
+    fn rest_user_repos(&self, user: &str) -> Result<Vec<String>> {
+        let owner = match self.api_call_request(&format!("users/{}", user)) {
+            Ok(owner) => owner,
+            Err(ref e) if e.code() == libc::ENOENT => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        // Without a usable token only the public repos of a user are listed.
+        let is_authenticated_user = match self.authenticated_user() {
+            Ok(login) => login.eq_ignore_ascii_case(user),
+            Err(e) => {
+                println!("Unable to look up the user of the token ({})", e);
+                false
             },
-            // Continue on to the next match below.
-            None => {},
+        };
+        let endpoint = if owner["type"] == "Organization" {
+            format!("orgs/{}/repos?type=all&per_page=100", user)
+        } else if is_authenticated_user {
+            // Private repos of a user are only listed for that user.
+            "user/repos?affiliation=owner&per_page=100".to_string()
+        } else {
+            format!("users/{}/repos?per_page=100", user)
+        };
+        let mut repos = Vec::new();
+        for e in self.api_call_all_pages(&endpoint)? {
+            repos.push(e["name"].as_str().ok_or(GitFSError::NoneError)?.to_string());
         }
-        let latest_commit = self.latest_commit_since(user, repo, end_time)?;
-        self.get_repo_or_create(repo).timestamp_to_sha = Some((end_time, latest_commit.clone()));
-        return self.create_fake_listing(user, repo, &latest_commit, repo_dir, cache_dir)
+        Ok(repos)
     }
 
-    // TODO: Start with a recent "since" and if no commits are found work backwards to find latest.
-    fn latest_commit_since(&self, user: &str, repo: &str, end_time: DateTime<Utc>) -> Result<String> {
-        // TODO: This looks 10000 days into the past which is arbitrary and slow.
-        let since = Utc::now().sub(Duration::days(10000));
-        let endpoint = format!("repos/{}/{}/commits?since={}&until={}", user, repo, since.to_rfc3339(), end_time.to_rfc3339());
-        let json = self.api_call_request(&endpoint)?;
-        if !json.is_array() {
-            if json.is_object() && json["message"].as_str()? == "Not Found" {
+    // Returns the login of the user who the token belongs to. The lock is not held during the
+    // request, so a slow request does not hold up the other threads.
+    fn authenticated_user(&self) -> Result<String> {
+        if let Some(login) = self.login.lock().unwrap().clone() {
+            return Ok(login);
+        }
+        let login = self.api_call_request("user")?["login"].as_str().ok_or(GitFSError::NoneError)?.to_string();
+        *self.login.lock().unwrap() = Some(login.clone());
+        Ok(login)
+    }
+
+    fn api_call_request(&self, endpoint: &str) -> Result<serde_json::value::Value> {
+        Ok(self.api_call_page(&format!("{}/{}", self.api_url, &endpoint))?.0)
+    }
+
+    // Requests every page of a list and returns all of the elements.
+    fn api_call_all_pages(&self, endpoint: &str) -> Result<Vec<Value>> {
+        let mut elements = Vec::new();
+        let mut url = Some(format!("{}/{}", self.api_url, &endpoint));
+        while let Some(page_url) = url {
+            let (json, next_url) = self.api_call_page(&page_url)?;
+            elements.extend(json.as_array().ok_or(GitFSError::NoneError)?.iter().cloned());
+            url = next_url;
+        }
+        Ok(elements)
+    }
+
+    // Returns the JSON at url and the URL of the next page of results, if there is one. A
+    // response which was seen before is revalidated rather than downloaded again.
+    fn api_call_page(&self, url: &str) -> Result<(Value, Option<String>)> {
+        println!("Request {}", url);
+        let cached = self.responses.get(url);
+        self.retry.run(url, || {
+            let res = self.send(|| {
+                let mut request = self.client.get(url)
+                    .header(USER_AGENT, "Virtual Git Filesystem")
+                    .header("Authorization", format!("token {}", self.token));
+                if let Some(cached) = &cached {
+                    if let Some(etag) = &cached.etag {
+                        request = request.header(IF_NONE_MATCH, etag.as_str());
+                    }
+                    if let Some(last_modified) = &cached.last_modified {
+                        request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+                    }
+                }
+                request
+            })?;
+            if res.status() == StatusCode::NOT_MODIFIED {
+                if let Some(cached) = &cached {
+                    println!("{} has not changed", url);
+                    return Ok((parse_json(&cached.body)?, cached.next_url.clone()));
+                }
+            }
+            res.error_for_status_ref()?;
+            let header = |name| res.headers().get(name).and_then(|value| value.to_str().ok()).map(String::from);
+            let etag = header(ETAG);
+            let last_modified = header(LAST_MODIFIED);
+            let next_url = header(LINK).as_deref().and_then(next_page_url);
+            let response = CachedResponse{
+                etag: etag,
+                last_modified: last_modified,
+                next_url: next_url,
+                body: res.text()?,
+            };
+            let json = parse_json(&response.body)?;
+            if response.etag.is_some() || response.last_modified.is_some() {
+                // The response is still usable if it could not be stored.
+                if let Err(e) = self.responses.put(url, &response) {
+                    eprintln!("Unable to cache the response for {}: {:?}", url, e);
+                }
+            }
+            Ok((json, response.next_url))
+        })
+    }
+
+    fn graphql_request(&self, query: &str, variables: Value) -> Result<Value> {
+        let url = self.graphql_url.clone();
+        println!("GraphQL request {} {}", url, variables);
+        let body = json!({"query": query, "variables": variables});
+        let json = self.retry.run(&url, || {
+            let res = self.send(|| {
+                self.client.post(&url)
+                    .header(USER_AGENT, "Virtual Git Filesystem")
+                    .header("Authorization", format!("bearer {}", self.token))
+                    .json(&body)
+            })?;
+            res.error_for_status_ref()?;
+            parse_json(&res.text()?)
+        })?;
+        if let Some(errors) = json["errors"].as_array() {
+            if errors.iter().any(|e| e["type"] == "NOT_FOUND") {
                 return Err(GitFSError::new("Not Found", libc::ENOENT));
             }
-            eprintln!("Invalid type for JSON result: {}", json);
-            return Err(GitFSError::new("Invalid JSON", libc::EINVAL));
+            // The GraphQL API reports an exceeded rate limit as an error rather than a status.
+            if errors.iter().any(|e| e["type"] == "RATE_LIMITED") {
+                return Err(GitFSError::new("API rate limit exceeded", libc::EAGAIN));
+            }
+            eprintln!("GraphQL errors: {}", json["errors"]);
+            return Err(GitFSError::new("GraphQL error", libc::EIO));
         }
-        // JSON elements will be sorted by most recent to least recent.
-        let most_recent_commit = &json.as_array()?[0];
-        return Ok(most_recent_commit["sha"].as_str().map(String::from)?);
+        Ok(json["data"].clone())
     }
 
-    fn create_fake_listing(&mut self, user: &str, repo_name: &str, commit_sha: &str, repo_dir: &str, cache_dir: &str) -> Result<()> {
-        let sha;
-        {
-            sha = match repo_dir {
-                "" => commit_sha.to_string(),
+    fn graphql_tree(&self, user: &str, repo: &str, sha: &str) -> Result<Tree> {
+        let data = self.graphql_request(TREE_QUERY, json!({"owner": user, "name": repo, "oid": sha}))?;
+        let object = &data["repository"]["object"];
+        let tree_json = match object["__typename"].as_str() {
+            Some("Commit") => &object["tree"],
+            Some("Tree") => object,
+            _ => return Err(GitFSError::new("Not Found", libc::ENOENT)),
+        };
+        let mut entries = Vec::new();
+        for node_json in tree_json["entries"].as_array().ok_or(GitFSError::NoneError)? {
+            let kind = match node_json["type"].as_str() {
+                Some("blob") => EntryKind::Blob,
+                Some("tree") => EntryKind::Tree,
                 _ => {
-                    let mut sha_result = self.get_repo_or_create(repo_name).tree.get(repo_dir);
-                    if sha_result.is_none() {
-                        let parent_dir = Path::new(repo_dir).parent().unwrap_or(Path::new("")).to_str()?;
-                        self.create_fake_listing(user, repo_name, commit_sha, parent_dir, cache_dir)?;
-                        // The directory should exist now that parent has been expanded. If it is
-                        // still None then it likely does not exist.
-                        sha_result = self.get_repo_or_create(repo_name).tree.get(repo_dir);
-                        if sha_result.is_none() {
-                            return Err(GitFSError::new("Not Found", libc::ENOENT));
-                        }
-                    }
-                    sha_result.unwrap().clone()
+                    eprintln!("Unknown type: {}", node_json["type"]);
+                    continue;
                 }
             };
-            if self.get_repo_or_create(repo_name).zero_files.contains(repo_dir) {
-                let url = format!("https://api.github.com/repos/{}/{}/git/blobs/{}", user, repo_name, sha);
-                let real_path = format!("{}/{}", cache_dir, repo_dir);
-                fs::create_dir_all(Path::new(&real_path).parent()?.to_str().unwrap())?;
-                self.download(&url, &real_path)?;
-                let repo = self.get_repo_or_create(repo_name);
-                repo.zero_files.remove(repo_dir);
-                repo.cloned_structures.insert(repo_dir.to_string());
-                return Ok(());
+            let blob_json = &node_json["object"];
+            // Trees do not have a size.
+            let size = blob_json["byteSize"].as_u64();
+            // The text is only the same as the file if it was not changed when it was decoded.
+            let content = match (blob_json["text"].as_str(), size) {
+                (Some(text), Some(size)) if size <= INLINE_BLOB_SIZE
+                    && blob_json["isBinary"] == false
+                    && blob_json["isTruncated"] == false
+                    && text.len() as u64 == size => Some(text.as_bytes().to_vec()),
+                _ => None,
+            };
+            entries.push(TreeEntry{
+                path: node_json["name"].as_str().ok_or(GitFSError::NoneError)?.to_string(),
+                kind: kind,
+                // The mode is the octal mode written in decimal.
+                mode: node_json["mode"].as_u64().ok_or(GitFSError::NoneError)? as u32,
+                size: size,
+                sha: node_json["oid"].as_str().ok_or(GitFSError::NoneError)?.to_string(),
+                content: content,
+            });
+        }
+        Ok(Tree{
+            sha: tree_json["oid"].as_str().ok_or(GitFSError::NoneError)?.to_string(),
+            entries: entries,
+        })
+    }
+
+    fn graphql_user_repos(&self, user: &str) -> Result<Vec<String>> {
+        let mut repos = Vec::new();
+        let mut cursor = Value::Null;
+        loop {
+            let data = self.graphql_request(REPOS_QUERY, json!({"login": user, "cursor": cursor}))?;
+            let repos_json = &data["repositoryOwner"]["repositories"];
+            // The user does not exist.
+            if repos_json.is_null() {
+                return Ok(repos);
+            }
+            for e in repos_json["nodes"].as_array().ok_or(GitFSError::NoneError)? {
+                repos.push(e["name"].as_str().ok_or(GitFSError::NoneError)?.to_string());
+            }
+            if repos_json["pageInfo"]["hasNextPage"] != true {
+                return Ok(repos);
             }
+            cursor = repos_json["pageInfo"]["endCursor"].clone();
         }
-        let tree_json = self.api_call_request(&format!("repos/{}/{}/git/trees/{}", user, repo_name, sha))?;
-        
+    }
+
+    // Returns a tree and whether Github truncated it, which happens for large recursive trees.
+    fn tree_request(&self, endpoint: &str) -> Result<(Tree, bool)> {
+        let tree_json = self.api_call_request(endpoint)?;
+
         // Check for an error message.
         let is_msg_null = tree_json["message"].is_null();
         if !is_msg_null {
             return Err(GitFSError::new(&format!("Error getting contents: {}", tree_json), libc::EIO));
         }
-        
-        let repo = self.get_repo_or_create(repo_name);
-        repo.tree.insert(repo_dir.to_string(), tree_json["sha"].as_str()?.to_string());
-
-        // Iterate over each entry in the directory listing.
-        for node_json in tree_json["tree"].as_array()? {
-            match node_json["type"].as_str() {
-                // blobs are files. write empty files of the correct size as placeholders.
-                Some("blob") => {
-                    let path = Path::new(repo_dir).join(node_json["path"].as_str()?);
-                    if repo.cloned_structures.contains(path.to_str()?) {
-                        println!("Skipping already cloned file: {}", path.to_str()?);
-                        continue;
-                    }
-                    let real_path = Path::new(cache_dir).join(path.as_path());
-                    // TODO: Use node_json["mode"].as_str() here.
-                    let mut file = fs::OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .mode(u32::from_str_radix(node_json["mode"].as_str()?, 8).unwrap())
-                        .open(real_path.as_path())?;
-                    let f_size = node_json["size"].as_i64()?;
-                    file.write_all(&vec![0; f_size.try_into().unwrap()])?;
-                    repo.zero_files.insert(path.to_str()?.to_string());
-                    repo.tree.insert(path.to_str()?.to_string(), node_json["sha"].as_str()?.to_string());
-                },
-                // Trees are directories. Simply create an empty directory.
-                Some("tree") => {
-                    let tree_sha = node_json["sha"].as_str()?.to_string();
-                    let path = Path::new(repo_dir).join(node_json["path"].as_str()?);
-                    repo.tree.insert(path.to_str()?.to_string(), tree_sha);
-                    // TODO: Use node_json["mode"].as_str() here.
-                    fs::create_dir_all(format!("{}/{}", cache_dir, path.to_str()?))?;
-                },
+
+        let mut entries = Vec::new();
+        for node_json in tree_json["tree"].as_array().ok_or(GitFSError::NoneError)? {
+            let kind = match node_json["type"].as_str() {
+                Some("blob") => EntryKind::Blob,
+                Some("tree") => EntryKind::Tree,
                 _ => {
-                    eprintln!("Unknown type: {}", node_json["type"])
+                    eprintln!("Unknown type: {}", node_json["type"]);
+                    continue;
                 }
-            }
+            };
+            entries.push(TreeEntry{
+                path: node_json["path"].as_str().ok_or(GitFSError::NoneError)?.to_string(),
+                kind: kind,
+                mode: u32::from_str_radix(node_json["mode"].as_str().ok_or(GitFSError::NoneError)?, 8).unwrap(),
+                // Trees do not have a size.
+                size: node_json["size"].as_u64(),
+                sha: node_json["sha"].as_str().ok_or(GitFSError::NoneError)?.to_string(),
+                content: None,
+            });
         }
-        // Create an empty .git directory. The contents will only be created when a file within
-        // this directory is accessed.
-        fs::create_dir_all(format!("{}/.git", cache_dir))?;
-        repo.cloned_structures.insert(repo_dir.to_string());
-        Ok(())
+        let tree = Tree{
+            sha: tree_json["sha"].as_str().ok_or(GitFSError::NoneError)?.to_string(),
+            entries: entries,
+        };
+        Ok((tree, tree_json["truncated"].as_bool().unwrap_or(false)))
     }
 
-    fn user_info(&self, user: &str) -> Result<serde_json::value::Value> {
-        let repos_endpoint = format!("users/{}/repos", user);
-        self.api_call_request(&repos_endpoint)
+    fn download(&self, remote_path: &str, local_path: &str) -> Result<()> {
+        self.retry.run(remote_path, || {
+            let mut resp = self.send(|| {
+                self.client.get(remote_path)
+                    .header(USER_AGENT, "Virtual Git Filesystem")
+                    .header("Authorization", format!("token {}", self.token))
+                    .header("Accept", "application/vnd.github.VERSION.raw")
+            })?;
+            resp.error_for_status_ref()?;
+            let mut out = File::create(local_path)?;
+            io::copy(&mut resp, &mut out)?;
+            Ok(())
+        })
     }
+}
 
-    // Creates the repo directories in the cache for a given user.
-    // TODO: Filter out repos created after sync time.
-    pub fn fill_user_repos(&mut self, path: &str, user: &str) -> Result<()> {
-        if self.fetched_users.contains(user) {
-            return Ok(())
+impl RemoteProvider for GithubFS {
+    fn list_user_repos(&self, user: &str) -> Result<Vec<String>> {
+        if self.graphql {
+            return self.graphql_user_repos(user);
         }
-        let json = self.user_info(user)?;
-        self.fetched_users.insert(user.to_string());
-        if json.as_array()?.len() > 0 {
-            fs::create_dir(&path)?;
+        self.rest_user_repos(user)
+    }
+
+    fn resolve_commit(&self, user: &str, repo: &str, reference: Option<&str>, end_time: Option<DateTime<Utc>>) -> Result<Option<String>> {
+        let result = match end_time {
+            // The commits endpoint accepts branches, tags and full or short shas. HEAD is the
+            // default branch.
+            None => self.api_call_request(&format!("repos/{}/{}/commits/{}", user, repo, encode_component(reference.unwrap_or("HEAD"))))
+                .and_then(|json| Ok(Some(json["sha"].as_str().ok_or(GitFSError::NoneError)?.to_string()))),
+            Some(end_time) => self.latest_commit_before(user, repo, reference, end_time),
+        };
+        match result {
+            // Github answers with a conflict when the repo is empty.
+            Err(GitFSError::ReqwestError(ref e)) if e.status() == Some(StatusCode::CONFLICT) => Ok(None),
+            result => result,
         }
-        for e in json.as_array()? {
-            let name = e["name"].as_str()?;
-            fs::create_dir(format!("{}/{}", path, name))?;
+    }
+
+    fn list_tree(&self, user: &str, repo: &str, sha: &str) -> Result<Tree> {
+        if self.graphql {
+            return self.graphql_tree(user, repo, sha);
         }
-        Ok(())
+        Ok(self.tree_request(&format!("repos/{}/{}/git/trees/{}", user, repo, sha))?.0)
     }
 
-    fn api_call_request(&self, endpoint: &str) -> Result<serde_json::value::Value> {
-        let url = format!("https://api.github.com/{}", &endpoint);
-        println!("Request {}", url);
-        let client = reqwest::blocking::Client::new();
-        let res = client.get(&url).header(reqwest::header::USER_AGENT, "Virtual Git Filesystem").header("Authorization", format!("token {}", self.token)).send()?;
-        res.error_for_status_ref()?;
-        let json_str = res.text()?;
-        match serde_json::from_str(&json_str) {
-            Ok(json) => Ok(json),
-            Err(e) => {
-                eprintln!("Unable to parse JSON: {}", e);
-                Err(GitFSError::new("Unable to parse JSON", libc::EINVAL))
-            }
+    fn list_tree_recursive(&self, user: &str, repo: &str, sha: &str) -> Result<Option<Tree>> {
+        // GraphQL listings are done one directory at a time, as they include the small files.
+        if self.graphql {
+            return Ok(None);
+        }
+        let (tree, truncated) = self.tree_request(&format!("repos/{}/{}/git/trees/{}?recursive=1", user, repo, sha))?;
+        if truncated {
+            println!("The tree of {}/{} is too large to list at once", user, repo);
+            return Ok(None);
         }
+        Ok(Some(tree))
     }
 
-    fn download(&self, remote_path: &str, local_path: &str) -> Result<()> {
-        let client = reqwest::blocking::Client::new();
-        let mut resp = client.get(remote_path)
-            .header(reqwest::header::USER_AGENT, "Virtual Git Filesystem")
-            .header("Authorization", format!("token {}", self.token))
-            .header("Accept", "application/vnd.github.VERSION.raw")
-            .send()?;
-        resp.error_for_status_ref()?;
-        let mut out = File::create(local_path)?;
-        io::copy(&mut resp, &mut out)?;
-        Ok(())
+    fn list_commits(&self, user: &str, repo: &str, sha: &str, count: usize) -> Result<Vec<CommitInfo>> {
+        let json = self.api_call_request(&format!("repos/{}/{}/commits?sha={}&per_page={}", user, repo, sha, count))?;
+        let mut commits = Vec::new();
+        for e in json.as_array().ok_or(GitFSError::NoneError)? {
+            let time = DateTime::parse_from_rfc3339(e["commit"]["committer"]["date"].as_str().ok_or(GitFSError::NoneError)?)
+                .map_err(|_| GitFSError::new("Invalid commit date", libc::EINVAL))?;
+            commits.push(CommitInfo{
+                sha: e["sha"].as_str().ok_or(GitFSError::NoneError)?.to_string(),
+                time: time.with_timezone(&Utc),
+                message: e["commit"]["message"].as_str().ok_or(GitFSError::NoneError)?.to_string(),
+            });
+        }
+        Ok(commits)
+    }
+
+    fn list_refs(&self, user: &str, repo: &str, kind: RefKind) -> Result<Vec<String>> {
+        let endpoint = match kind {
+            RefKind::Branch => "branches",
+            RefKind::Tag => "tags",
+        };
+        let mut names = Vec::new();
+        for e in self.api_call_all_pages(&format!("repos/{}/{}/{}?per_page=100", user, repo, endpoint))? {
+            names.push(e["name"].as_str().ok_or(GitFSError::NoneError)?.to_string());
+        }
+        Ok(names)
+    }
+
+    fn fetch_blob(&self, user: &str, repo: &str, sha: &str, local_path: &str) -> Result<()> {
+        let url = format!("{}/repos/{}/{}/git/blobs/{}", self.api_url, user, repo, sha);
+        self.download(&url, local_path)
+    }
+
+    fn clone_url(&self, user: &str, repo: &str) -> String {
+        self.remote.clone_url(user, repo)
+    }
+
+    fn git_remote(&self) -> &SmartHttpFS {
+        &self.remote
+    }
+
+    fn rate_limits(&self) -> Vec<RateLimit> {
+        self.rate_limits.lock().unwrap().values().cloned().collect()
+    }
+}
+
+// Returns the URL of the GraphQL API for the REST API at api_url. Github Enterprise serves the
+// REST API at <host>/api/v3 and the GraphQL API at <host>/api/graphql, while github.com (and a
+// mock server) serve it at <api_url>/graphql.
+fn graphql_url(api_url: &str) -> String {
+    match api_url.strip_suffix("/api/v3") {
+        Some(host_url) => format!("{}/api/graphql", host_url),
+        None => format!("{}/graphql", api_url),
+    }
+}
+
+// Returns the URL of the site for the REST API at api_url, which is api.<host> on github.com
+// and <host>/api/v3 on Github Enterprise.
+fn web_url(api_url: &str) -> String {
+    if let Some(host_url) = api_url.strip_suffix("/api/v3") {
+        return host_url.to_string();
+    }
+    match api_url.find("://api.") {
+        Some(i) => format!("{}://{}", &api_url[..i], &api_url[i + "://api.".len()..]),
+        None => api_url.to_string(),
+    }
+}
+
+// Returns the host of the site for the REST API at api_url, along with its port if it has one.
+fn web_host(api_url: &str) -> String {
+    let web_url = web_url(api_url);
+    let address = web_url.split("://").nth(1).unwrap_or(&web_url);
+    address.split('/').next().unwrap_or(address).to_string()
+}
+
+// Returns the URL of the next page from a Link header, which looks like
+// <https://api.github.com/user/repos?page=2>; rel="next", <...>; rel="last".
+fn next_page_url(link: &str) -> Option<String> {
+    let next = link.split(',').find(|part| part.contains("rel=\"next\""))?;
+    Some(next[next.find('<')? + 1..next.find('>')?].to_string())
+}
+
+// Returns the number of seconds until a rate limit is lifted, or None if res was not rate limited.
+// Github answers with a 403 when the quota is used up, and with a 403 or 429 and a Retry-After
+// header when too many requests are made at once.
+fn rate_limit_wait(res: &Response) -> Option<u64> {
+    if res.status() != StatusCode::FORBIDDEN && res.status() != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let header = |name| res.headers().get(name).and_then(|value| value.to_str().ok());
+    if let Some(retry_after) = header(RETRY_AFTER.as_str()).and_then(|value| value.parse::<u64>().ok()) {
+        return Some(retry_after);
+    }
+    if header("x-ratelimit-remaining") == Some("0") {
+        let reset = header("x-ratelimit-reset")?.parse::<i64>().ok()?;
+        // Wait for one extra second, as the reset time is rounded down.
+        return Some((reset - Utc::now().timestamp()).max(0) as u64 + 1);
+    }
+    if res.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Some(0);
+    }
+    None
+}
+
+// Percent-encodes everything but the unreserved characters of a URL, so that a ref such as
+// feature/a+b can be used as a path segment or a query parameter.
+fn encode_component(component: &str) -> String {
+    let mut encoded = String::new();
+    for byte in component.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn parse_json(json_str: &str) -> Result<Value> {
+    match serde_json::from_str(json_str) {
+        Ok(json) => Ok(json),
+        Err(e) => {
+            eprintln!("Unable to parse JSON: {}", e);
+            Err(GitFSError::new("Unable to parse JSON", libc::EINVAL))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tempdir::TempDir;
+
+    // Serves the GraphQL API from respond, which is given the query and variables of each request.
+    // Returns the API URL of the server.
+    fn serve_graphql<F>(respond: F) -> String
+        where F: Fn(&str, &Value) -> Value + Send + Sync + 'static {
+        let server = rouille::Server::new("127.0.0.1:0", move |request| {
+            assert_eq!(request.url(), "/graphql");
+            let mut body = String::new();
+            request.data().unwrap().read_to_string(&mut body).unwrap();
+            let body: Value = serde_json::from_str(&body).unwrap();
+            rouille::Response::json(&respond(body["query"].as_str().unwrap(), &body["variables"]))
+        }).unwrap();
+        let api_url = format!("http://{}", server.server_addr());
+        thread::spawn(move || server.run());
+        api_url
+    }
+
+    fn graphql_github(dir: &TempDir, api_url: &str) -> GithubFS {
+        GithubFS::new(String::new(), dir.path().to_str().unwrap(), api_url, true, RetryPolicy::default())
+    }
+
+    #[test]
+    fn lists_every_page_of_owned_repos() {
+        let api_url = serve_graphql(|query, variables| {
+            assert!(query.contains("ownerAffiliations: [OWNER]"));
+            assert_eq!(variables["login"], "octocat");
+            let (names, next) = match variables["cursor"].as_str() {
+                None => (vec!["hello", "world"], Some("page2")),
+                Some("page2") => (vec!["spoon"], None),
+                Some(cursor) => panic!("Unexpected cursor {}", cursor),
+            };
+            let nodes: Vec<Value> = names.iter().map(|name| json!({"name": name})).collect();
+            json!({"data": {"repositoryOwner": {"repositories": {
+                "nodes": nodes,
+                "pageInfo": {"hasNextPage": next.is_some(), "endCursor": next},
+            }}}})
+        });
+        let dir = TempDir::new("vg").unwrap();
+        let github = graphql_github(&dir, &api_url);
+        assert_eq!(github.list_user_repos("octocat").unwrap(), vec!["hello", "world", "spoon"]);
+    }
+
+    #[test]
+    fn lists_no_repos_of_missing_owner() {
+        let api_url = serve_graphql(|_, _| json!({"data": {"repositoryOwner": null}}));
+        let dir = TempDir::new("vg").unwrap();
+        let github = graphql_github(&dir, &api_url);
+        assert!(github.list_user_repos("nobody").unwrap().is_empty());
+    }
+
+    #[test]
+    fn lists_tree_with_small_text_files() {
+        let api_url = serve_graphql(|query, variables| {
+            assert!(query.contains("repository(owner: $owner"));
+            assert_eq!(variables, &json!({"owner": "octocat", "name": "hello", "oid": "c0ffee"}));
+            json!({"data": {"repository": {"object": {"__typename": "Commit", "tree": {
+                "oid": "7ree",
+                "entries": [
+                    {"name": "README", "type": "blob", "mode": 33188, "oid": "aaa",
+                     "object": {"byteSize": 12, "isBinary": false, "isTruncated": false, "text": "hello world\n"}},
+                    {"name": "logo.png", "type": "blob", "mode": 33188, "oid": "bbb",
+                     "object": {"byteSize": 3, "isBinary": true, "isTruncated": false, "text": null}},
+                    {"name": "run.sh", "type": "blob", "mode": 33261, "oid": "ccc",
+                     "object": {"byteSize": 99, "isBinary": false, "isTruncated": false, "text": "changed"}},
+                    {"name": "src", "type": "tree", "mode": 16384, "oid": "ddd", "object": {}},
+                    {"name": "lib", "type": "commit", "mode": 57344, "oid": "eee", "object": {}},
+                ],
+            }}}}})
+        });
+        let dir = TempDir::new("vg").unwrap();
+        let github = graphql_github(&dir, &api_url);
+        let tree = github.list_tree("octocat", "hello", "c0ffee").unwrap();
+        assert_eq!(tree.sha, "7ree");
+        let names: Vec<&str> = tree.entries.iter().map(|entry| entry.path.as_str()).collect();
+        // Submodules are skipped.
+        assert_eq!(names, vec!["README", "logo.png", "run.sh", "src"]);
+        assert_eq!(tree.entries[0].mode, 0o100644);
+        assert_eq!(tree.entries[0].size, Some(12));
+        assert_eq!(tree.entries[0].content, Some(b"hello world\n".to_vec()));
+        // Binary files and text which does not match the size of the file are fetched later.
+        assert_eq!(tree.entries[1].content, None);
+        assert_eq!(tree.entries[2].mode, 0o100755);
+        assert_eq!(tree.entries[2].content, None);
+        assert!(matches!(tree.entries[3].kind, EntryKind::Tree));
+        assert_eq!(tree.entries[3].size, None);
+    }
+
+    #[test]
+    fn reports_missing_graphql_objects() {
+        let api_url = serve_graphql(|_, _| json!({
+            "data": {"repository": null},
+            "errors": [{"type": "NOT_FOUND", "message": "Could not resolve to a Repository"}],
+        }));
+        let dir = TempDir::new("vg").unwrap();
+        let github = graphql_github(&dir, &api_url);
+        assert_eq!(github.list_tree("octocat", "missing", "c0ffee").err().unwrap().code(), libc::ENOENT);
+    }
+
+    #[test]
+    fn reports_graphql_rate_limit() {
+        let api_url = serve_graphql(|_, _| json!({"errors": [{"type": "RATE_LIMITED", "message": "API rate limit exceeded"}]}));
+        let dir = TempDir::new("vg").unwrap();
+        let github = graphql_github(&dir, &api_url);
+        assert_eq!(github.list_user_repos("octocat").err().unwrap().code(), libc::EAGAIN);
+    }
+
+    #[test]
+    fn derives_github_urls() {
+        assert_eq!(graphql_url(GITHUB_API_URL), "https://api.github.com/graphql");
+        assert_eq!(web_url(GITHUB_API_URL), "https://github.com");
+        assert_eq!(web_host(GITHUB_API_URL), "github.com");
+    }
+
+    #[test]
+    fn derives_enterprise_urls() {
+        assert_eq!(graphql_url("https://git.example.com/api/v3"), "https://git.example.com/api/graphql");
+        assert_eq!(web_url("https://git.example.com/api/v3"), "https://git.example.com");
+        assert_eq!(web_host("https://git.example.com/api/v3"), "git.example.com");
+    }
+
+    #[test]
+    fn derives_mock_server_urls() {
+        assert_eq!(graphql_url("http://localhost:8080"), "http://localhost:8080/graphql");
+        assert_eq!(web_url("http://localhost:8080"), "http://localhost:8080");
+        assert_eq!(web_host("http://localhost:8080"), "localhost:8080");
+    }
+
+    #[test]
+    fn encodes_refs() {
+        assert_eq!(encode_component("main"), "main");
+        assert_eq!(encode_component("v1.0-rc_1~2"), "v1.0-rc_1~2");
+        assert_eq!(encode_component("feature/a+b c#1"), "feature%2Fa%2Bb%20c%231");
+        assert_eq!(encode_component("caf\u{e9}"), "caf%C3%A9");
+    }
+
+    #[test]
+    fn clones_from_enterprise_server() {
+        let github = GithubFS::new(String::new(), "/nonexistent", "https://git.example.com/api/v3", false, RetryPolicy::default());
+        assert_eq!(github.clone_url("octocat", "hello"), "https://git.example.com/octocat/hello.git");
+        assert_eq!(github.git_remote().clone_url("octocat", "hello"), "https://git.example.com/octocat/hello.git");
+    }
+
+    #[test]
+    fn lists_public_repos_when_token_user_is_unknown() {
+        let server = rouille::Server::new("127.0.0.1:0", |request| {
+            match request.url().as_str() {
+                "/users/octocat" => rouille::Response::json(&json!({"login": "octocat", "type": "User"})),
+                "/user" => rouille::Response::json(&json!({"message": "Bad credentials"})).with_status_code(401),
+                "/users/octocat/repos" => rouille::Response::json(&json!([{"name": "hello"}, {"name": "world"}])),
+                _ => rouille::Response::empty_404(),
+            }
+        }).unwrap();
+        let api_url = format!("http://{}", server.server_addr());
+        thread::spawn(move || server.run());
+        let dir = TempDir::new("vg").unwrap();
+        let github = GithubFS::new("invalid".to_string(), dir.path().to_str().unwrap(), &api_url, false, RetryPolicy::default());
+        assert_eq!(github.list_user_repos("octocat").unwrap(), vec!["hello", "world"]);
+    }
+
+    #[test]
+    fn finds_next_page_url() {
+        let link = "<https://api.github.com/user/repos?page=2>; rel=\"next\", \
+                    <https://api.github.com/user/repos?page=5>; rel=\"last\"";
+        assert_eq!(next_page_url(link), Some("https://api.github.com/user/repos?page=2".to_string()));
+    }
+
+    #[test]
+    fn finds_next_page_url_after_other_links() {
+        let link = "<https://api.github.com/user/repos?page=1>; rel=\"prev\", \
+                    <https://api.github.com/user/repos?page=3>; rel=\"next\"";
+        assert_eq!(next_page_url(link), Some("https://api.github.com/user/repos?page=3".to_string()));
+    }
+
+    #[test]
+    fn has_no_next_page_on_last_page() {
+        let link = "<https://api.github.com/user/repos?page=1>; rel=\"first\", \
+                    <https://api.github.com/user/repos?page=4>; rel=\"prev\"";
+        assert_eq!(next_page_url(link), None);
+        assert_eq!(next_page_url(""), None);
+        assert_eq!(next_page_url("rel=\"next\""), None);
     }
 }
@@ -0,0 +1,639 @@
+use std::fs;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use crate::blob_store::{BlobStore, Link};
+use crate::error::{GitFSError, Result};
+use crate::index::{Index};
+use crate::locks::{KeyedLocks, SharedLocks};
+use crate::libc_extras::libc;
+use crate::provider::{EntryKind, RefKind, RemoteProvider};
+use crate::overlay::{CHANGES_DIR};
+use crate::repo_id::{RepoId};
+use std::os::unix::fs::symlink;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// The directory inside of each repo which links to every branch and tag.
+pub const REFS_DIR: &str = ".refs";
+
+// The directory inside of each repo which links to its recent commits.
+pub const HISTORY_DIR: &str = ".history";
+
+// The directories which the mount adds at the root of each repo. A directory of the repo with
+// the same name is shown instead.
+pub const METADATA_DIRS: [&str; 4] = [".git", REFS_DIR, HISTORY_DIR, CHANGES_DIR];
+
+// The number of commits shown in the history directory.
+const HISTORY_LENGTH: usize = 100;
+
+// The commit recorded in the index for a repo directory which does not have any commits.
+const NO_COMMIT: &str = "";
+
+// The number of seconds for which the commit of a repo directory which follows a branch is used
+// before the branch is looked up again. Its refs and history, and the repos of a user, are listed
+// again after the same time.
+const REFRESH_SECONDS: i64 = 600;
+
+// The revision of a repo which a repo directory shows.
+pub struct Revision {
+    // A branch, tag or sha. None for the default branch.
+    pub reference: Option<String>,
+    // Only commits made before this time are shown.
+    pub end_time: Option<DateTime<Utc>>,
+}
+
+// Parses either a date (2020-06-01, which is midnight UTC) or an RFC 3339 time.
+pub fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?));
+    }
+    DateTime::parse_from_rfc3339(time).ok().map(|t| t.with_timezone(&Utc))
+}
+
+// Splits a repo directory name into the repo name and the revision. The name may be followed by
+// @<ref>, @{<time>} or @<ref>@{<time>}. Slashes in the reference are written as %2F.
+pub fn split_repo_name(repo: &str) -> (&str, Revision) {
+    let mut revision = Revision{
+        reference: None,
+        end_time: None,
+    };
+    let i = match repo.find('@') {
+        Some(i) => i,
+        None => return (repo, revision),
+    };
+    let mut suffix = &repo[i + 1..];
+    if suffix.ends_with('}') {
+        if let Some(brace) = suffix.rfind('{') {
+            let end_time = parse_time(&suffix[brace + 1..suffix.len() - 1]);
+            if end_time.is_some() && (brace == 0 || suffix[..brace].ends_with('@')) {
+                revision.end_time = end_time;
+                suffix = &suffix[..brace.saturating_sub(1)];
+            }
+        }
+    }
+    if !suffix.is_empty() {
+        revision.reference = Some(suffix.replace("%2F", "/"));
+    }
+    (&repo[..i], revision)
+}
+
+// Mirrors the repos of a single hosting provider into the cache directory. It is shared by all of
+// the FUSE threads.
+pub struct HostFS {
+    host: String,
+    provider: Box<dyn RemoteProvider>,
+    // The index is only locked while it is read or updated, never while waiting on the network.
+    // It is shared with the eviction of the blob store.
+    index: Arc<Mutex<Index>>,
+    blobs: BlobStore,
+    // Serializes the work on each part of a repo (e.g. listing a directory).
+    locks: KeyedLocks,
+    // Work on any part of a repo shares its lock, which refresh holds alone while it drops
+    // everything that was cached for the repo.
+    repo_locks: SharedLocks,
+}
+
+impl HostFS {
+    pub fn new(host: &str, provider: Box<dyn RemoteProvider>, index: Arc<Mutex<Index>>, blobs: BlobStore) -> HostFS {
+        HostFS{
+            host: host.to_string(),
+            provider: provider,
+            index: index,
+            blobs: blobs,
+            locks: KeyedLocks::new(),
+            repo_locks: SharedLocks::new(),
+        }
+    }
+
+    fn index(&self) -> MutexGuard<'_, Index> {
+        self.index.lock().unwrap()
+    }
+
+    // Runs f while holding the lock for one part of a repo, such as a directory in it. The repo
+    // is not reset by refresh while f runs.
+    pub fn with_repo_lock<T, F>(&self, repo: &RepoId, part: &str, f: F) -> T
+        where F: FnOnce() -> T {
+        self.repo_locks.with_shared_lock(&repo.to_string(), || {
+            self.locks.with_lock(&format!("repo:{}:{}", repo, part), f)
+        })
+    }
+
+    // Runs f while holding the lock for resolving the commit of a repo. It does not share the
+    // lock of the repo, so that refresh can take it while holding the repo lock alone.
+    fn with_resolve_lock<T, F>(&self, repo: &RepoId, f: F) -> T
+        where F: FnOnce() -> T {
+        self.locks.with_lock(&format!("repo:{}:resolve", repo), f)
+    }
+
+    // Returns the identity of a repo directory of an owner on this host.
+    pub fn repo_id(&self, owner: &str, dir_name: &str) -> RepoId {
+        RepoId::new(&self.host, owner, dir_name)
+    }
+
+    pub fn provider(&self) -> &dyn RemoteProvider {
+        &*self.provider
+    }
+
+    pub fn is_structure_cloned(&self, repo: &RepoId, repo_dir: &str) -> bool {
+        self.index().is_cloned(repo, repo_dir)
+    }
+
+    // Returns true if the placeholder for repo_file does not have the size of the real file.
+    pub fn is_unsized(&self, repo: &RepoId, repo_file: &str) -> bool {
+        self.index().is_unsized(repo, repo_file)
+    }
+
+    pub fn mark_as_cloned(&self, repo: &RepoId, repo_file: &str) -> Result<()> {
+        self.index().mark_cloned(repo, repo_file)
+    }
+
+    // Returns true if repo_file is a placeholder whose contents have not been fetched.
+    pub fn is_placeholder(&self, repo: &RepoId, repo_file: &str) -> bool {
+        self.index().is_zero(repo, repo_file)
+    }
+
+    // Returns true if name, at the root of a repo directory, is a directory which the mount adds
+    // rather than a file or directory of the repo. The root of the repo must have been listed.
+    pub fn is_metadata_dir(&self, repo: &RepoId, name: &str) -> bool {
+        METADATA_DIRS.contains(&name) && self.index().tree_sha(repo, name).is_none()
+    }
+
+    // Returns the sha of the blob of a file of the repo, if its directory has been listed.
+    pub fn blob_sha(&self, repo: &RepoId, repo_file: &str) -> Option<String> {
+        self.index().tree_sha(repo, repo_file)
+    }
+
+    // Returns a file or directory of the repo whose git object has the given sha, if the repo
+    // has one which has been listed.
+    pub fn path_of(&self, repo: &RepoId, sha: &str) -> Option<String> {
+        self.index().path_of(repo, sha)
+    }
+
+    // Returns the commit shown by a repo directory, or None if the repo does not have any commits.
+    // The default branch, or the branch or tag which the directory names, is shown as of end_time
+    // (or at its current head if end_time is None), unless the directory names its own time.
+    pub fn resolve(&self, repo: &RepoId, end_time: Option<DateTime<Utc>>) -> Result<Option<String>> {
+        if let Some(sha) = self.index().commit(repo) {
+            println!("Already has timestamp");
+            return Ok(stored_commit(sha));
+        }
+        self.with_resolve_lock(repo, || {
+            if let Some(sha) = self.index().commit(repo) {
+                return Ok(stored_commit(sha));
+            }
+            let revision = repo.revision();
+            let end_time = match revision {
+                Revision{end_time: Some(end_time), ..} => Some(end_time),
+                // A sha is shown as it is, while a branch or tag is shown as of end_time.
+                Revision{reference: Some(ref reference), ..} if is_sha(reference) => None,
+                _ => end_time,
+            };
+            let latest_commit = self.provider.resolve_commit(&repo.owner, &repo.name, revision.reference.as_deref(), end_time)?;
+            let sha = latest_commit.as_deref().unwrap_or(NO_COMMIT);
+            self.index().set_commit(repo, end_time.unwrap_or(Utc::now()), sha)?;
+            Ok(latest_commit)
+        })
+    }
+
+    // Looks up the branch or tag which a repo directory follows again if its commit was resolved
+    // more than REFRESH_SECONDS ago. If it has moved, everything which was cached for the
+    // directory at repo_path is dropped, so that it is listed again at the new commit. Its refs
+    // and history are listed again either way. Directories which are shown at a point in time or
+    // at a sha never change.
+    pub fn refresh(&self, repo: &RepoId, repo_path: &str, end_time: Option<DateTime<Utc>>) -> Result<()> {
+        if end_time.is_some() || !follows_ref(&repo.revision()) || self.is_fresh(repo) {
+            return Ok(());
+        }
+        // Nothing else is done with the repo while it may be reset.
+        let refreshed = self.repo_locks.with_exclusive_lock(&repo.to_string(), || self.with_resolve_lock(repo, || -> Result<bool> {
+            // Another thread may have refreshed it while this one was waiting for the lock.
+            if self.is_fresh(repo) {
+                return Ok(false);
+            }
+            let reference = repo.revision().reference;
+            let latest_commit = match self.provider.resolve_commit(&repo.owner, &repo.name, reference.as_deref(), None) {
+                Ok(latest_commit) => latest_commit,
+                // What is in the cache is served until the host can be reached again.
+                Err(e) => {
+                    println!("Unable to refresh {} ({})", repo, e);
+                    return Ok(false);
+                },
+            };
+            let sha = latest_commit.as_deref().unwrap_or(NO_COMMIT);
+            if self.index().commit(repo).as_deref() != Some(sha) {
+                println!("{} moved to {}", repo, sha);
+                self.index().reset(repo)?;
+                clear_dir(repo_path)?;
+            }
+            self.index().set_commit(repo, Utc::now(), sha)?;
+            Ok(true)
+        }))?;
+        if !refreshed {
+            return Ok(());
+        }
+        for structure in &[format!("{}/branches", REFS_DIR), format!("{}/tags", REFS_DIR), HISTORY_DIR.to_string()] {
+            self.with_repo_lock(repo, structure, || -> Result<()> {
+                // The repo may have a directory of its own with the same name.
+                let name = structure.split('/').next().unwrap_or("");
+                if !self.is_metadata_dir(repo, name) || !self.is_structure_cloned(repo, structure) {
+                    return Ok(());
+                }
+                self.index().mark_expired(repo, structure)?;
+                clear_dir(&format!("{}/{}", repo_path, structure))
+            })?;
+        }
+        Ok(())
+    }
+
+    // Returns true if the commit of a repo directory was resolved less than REFRESH_SECONDS ago,
+    // or has not been resolved at all.
+    fn is_fresh(&self, repo: &RepoId) -> bool {
+        self.index().commit_time(repo).is_none_or(|time| !is_expired(time))
+    }
+
+    // Clones a specific directory inside of a repo, saving the empty files to the cache.
+    pub fn clone_dir(&self, repo_dir: &str, cache_dir: &str, repo: &RepoId, end_time: Option<DateTime<Utc>>) -> Result<()> {
+        // TODO: Do not create dirs that do not exist.
+        fs::create_dir_all(cache_dir)?;
+        // The repo is not reset between resolving its commit and listing the directory at it.
+        self.repo_locks.with_shared_lock(&repo.to_string(), || {
+            let latest_commit = match self.resolve(repo, end_time)? {
+                Some(sha) => sha,
+                // A repo without any commits is an empty directory.
+                None if repo_dir.is_empty() => return self.create_empty_listing(repo, cache_dir),
+                None => return Err(GitFSError::new("Not Found", libc::ENOENT)),
+            };
+            self.create_fake_listing(repo, &latest_commit, repo_dir, cache_dir)
+        })
+    }
+
+    fn create_empty_listing(&self, repo: &RepoId, cache_dir: &str) -> Result<()> {
+        self.with_repo_lock(repo, "", || {
+            if self.index().is_cloned(repo, "") {
+                return Ok(());
+            }
+            self.create_metadata_dirs(repo, cache_dir)?;
+            self.index().mark_cloned(repo, "")
+        })
+    }
+
+    fn create_fake_listing(&self, repo: &RepoId, commit_sha: &str, repo_dir: &str, cache_dir: &str) -> Result<()> {
+        let sha = match repo_dir {
+            "" => commit_sha.to_string(),
+            _ => {
+                let mut sha_result = self.index().tree_sha(repo, repo_dir);
+                if sha_result.is_none() {
+                    let parent_dir = Path::new(repo_dir).parent().unwrap_or(Path::new("")).to_str().ok_or(GitFSError::NoneError)?;
+                    self.create_fake_listing(repo, commit_sha, parent_dir, cache_dir)?;
+                    // The directory should exist now that parent has been expanded. If it is
+                    // still None then it likely does not exist.
+                    sha_result = self.index().tree_sha(repo, repo_dir);
+                    if sha_result.is_none() {
+                        return Err(GitFSError::new("Not Found", libc::ENOENT));
+                    }
+                }
+                sha_result.unwrap()
+            }
+        };
+        self.with_repo_lock(repo, repo_dir, || {
+            // Another thread may have done the work while this one was waiting for the lock.
+            if self.index().is_cloned(repo, repo_dir) {
+                return Ok(());
+            }
+            if self.index().is_zero(repo, repo_dir) {
+                let real_path = format!("{}/{}", cache_dir, repo_dir);
+                fs::create_dir_all(Path::new(&real_path).parent().ok_or(GitFSError::NoneError)?.to_str().unwrap())?;
+                let provider = &self.provider;
+                let link = Link::new(repo, repo_dir, &real_path);
+                self.blobs.link(&sha, link, |blob_path| {
+                    provider.fetch_blob(&repo.owner, &repo.name, &sha, blob_path)
+                })?;
+                self.index().mark_cloned(repo, repo_dir)?;
+                return Ok(());
+            }
+            self.list_dir(repo, &sha, repo_dir, cache_dir)
+        })
+    }
+
+    // Writes a placeholder for each file in a directory and creates its subdirectories. The root
+    // of a repo is listed recursively if the provider supports it, so that every directory in the
+    // repo is listed with a single request.
+    fn list_dir(&self, repo: &RepoId, sha: &str, repo_dir: &str, cache_dir: &str) -> Result<()> {
+        let recursive_tree = match repo_dir {
+            "" => self.provider.list_tree_recursive(&repo.owner, &repo.name, sha)?,
+            _ => None,
+        };
+        let recursive = recursive_tree.is_some();
+        let tree = match recursive_tree {
+            Some(tree) => tree,
+            None => self.provider.list_tree(&repo.owner, &repo.name, sha)?,
+        };
+
+        self.index().add_tree(repo, repo_dir, &tree.sha)?;
+        let mut listed_dirs = vec![repo_dir.to_string()];
+
+        // Iterate over each entry in the directory listing.
+        for entry in tree.entries {
+            let path = Path::new(repo_dir).join(&entry.path);
+            match entry.kind {
+                // blobs are files. write sparse files of the correct size as placeholders. No
+                // data is written, and the contents are fetched before the file is read.
+                EntryKind::Blob => {
+                    if self.index().is_cloned(repo, path.to_str().ok_or(GitFSError::NoneError)?) {
+                        println!("Skipping already cloned file: {}", path.to_str().ok_or(GitFSError::NoneError)?);
+                        continue;
+                    }
+                    // The placeholder is recorded first so that it is never mistaken for the
+                    // real file after a restart.
+                    self.index().add_placeholder(repo, path.to_str().ok_or(GitFSError::NoneError)?, &entry.sha, entry.size.is_some())?;
+                    let real_path = Path::new(cache_dir).join(path.as_path());
+                    if recursive {
+                        fs::create_dir_all(real_path.parent().ok_or(GitFSError::NoneError)?)?;
+                    }
+                    let file = fs::OpenOptions::new()
+                        .create(true)
+                        .write(true)
+                        .mode(entry.mode)
+                        .open(real_path.as_path())?;
+                    file.set_len(entry.size.unwrap_or(0))?;
+                    // Small files may come with the listing, in which case they are stored now.
+                    if let Some(content) = entry.content {
+                        let link = Link::new(repo, path.to_str().ok_or(GitFSError::NoneError)?, real_path.to_str().ok_or(GitFSError::NoneError)?);
+                        self.blobs.link(&entry.sha, link, |blob_path| Ok(fs::write(blob_path, &content)?))?;
+                        self.index().mark_cloned(repo, path.to_str().ok_or(GitFSError::NoneError)?)?;
+                    }
+                },
+                // Trees are directories. Simply create an empty directory.
+                EntryKind::Tree => {
+                    self.index().add_tree(repo, path.to_str().ok_or(GitFSError::NoneError)?, &entry.sha)?;
+                    // TODO: Use entry.mode here.
+                    fs::create_dir_all(format!("{}/{}", cache_dir, path.to_str().ok_or(GitFSError::NoneError)?))?;
+                    if recursive {
+                        listed_dirs.push(path.to_str().ok_or(GitFSError::NoneError)?.to_string());
+                    }
+                },
+            }
+        }
+        self.create_metadata_dirs(repo, cache_dir)?;
+        for dir in listed_dirs {
+            self.index().mark_cloned(repo, &dir)?;
+        }
+        Ok(())
+    }
+
+    // Creates the directories which the mount adds at the root of a repo, unless the repo has its
+    // own. They are empty, and are only filled when something in them is accessed.
+    fn create_metadata_dirs(&self, repo: &RepoId, cache_dir: &str) -> Result<()> {
+        for name in METADATA_DIRS.iter() {
+            if self.is_metadata_dir(repo, name) {
+                fs::create_dir_all(format!("{}/{}", cache_dir, name))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Creates a symlink in refs_dir to the <repo>@<ref> directory of each branch or tag.
+    pub fn fill_refs(&self, refs_dir: &str, repo: &RepoId, kind: RefKind) -> Result<()> {
+        let kind_dir = match kind {
+            RefKind::Branch => "branches",
+            RefKind::Tag => "tags",
+        };
+        let refs_structure = format!("{}/{}", REFS_DIR, kind_dir);
+        self.with_repo_lock(repo, &refs_structure, || {
+            if self.is_structure_cloned(repo, &refs_structure) {
+                return Ok(())
+            }
+            for name in self.provider.list_refs(&repo.owner, &repo.name, kind)? {
+                let link = Path::new(refs_dir).join(kind_dir).join(&name);
+                fs::create_dir_all(link.parent().ok_or(GitFSError::NoneError)?)?;
+                // The link is at <repo>/.refs/<kind>/<name>, and the name may contain slashes.
+                let depth = 3 + name.matches('/').count();
+                let target = format!("{}{}@{}", "../".repeat(depth), repo.name, name.replace("/", "%2F"));
+                if let Err(e) = symlink(&target, &link) {
+                    if e.kind() != io::ErrorKind::AlreadyExists {
+                        return Err(e.into());
+                    }
+                }
+            }
+            self.mark_as_cloned(repo, &refs_structure)?;
+            Ok(())
+        })
+    }
+
+    // Fills history_dir with a symlink to the <repo>@<sha> directory of each recent commit, plus
+    // links to those in by-date/<time>_<short sha> and by-message/<short sha>_<subject>.
+    pub fn fill_history(&self, history_dir: &str, repo: &RepoId, end_time: Option<DateTime<Utc>>) -> Result<()> {
+        self.with_repo_lock(repo, HISTORY_DIR, || {
+            if self.is_structure_cloned(repo, HISTORY_DIR) {
+                return Ok(())
+            }
+            // A repo without any commits has an empty history.
+            let commits = match self.resolve(repo, end_time)? {
+                Some(sha) => self.provider.list_commits(&repo.owner, &repo.name, &sha, HISTORY_LENGTH)?,
+                None => Vec::new(),
+            };
+            for commit in commits {
+                let short_sha = &commit.sha[..7];
+                let subject: String = commit.message.lines().next().unwrap_or("").chars()
+                    .take(50)
+                    .map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+                    .collect();
+                let links = vec![
+                    (commit.sha.clone(), format!("../../{}@{}", repo.name, commit.sha)),
+                    (format!("by-date/{}_{}", commit.time.format("%Y-%m-%dT%H:%M:%SZ"), short_sha), format!("../{}", commit.sha)),
+                    (format!("by-message/{}_{}", short_sha, subject), format!("../{}", commit.sha)),
+                ];
+                for (name, target) in links {
+                    if let Err(e) = symlink(&target, format!("{}/{}", history_dir, name)) {
+                        if e.kind() != io::ErrorKind::AlreadyExists {
+                            return Err(e.into());
+                        }
+                    }
+                }
+            }
+            self.mark_as_cloned(repo, HISTORY_DIR)?;
+            Ok(())
+        })
+    }
+
+    // Creates the repo directories in the cache for a given user. The repos are listed again after
+    // REFRESH_SECONDS, so that new repos show up. If the provider can not list repos, the
+    // directory of the user is only created along with the first of its repos that is found.
+    // TODO: Filter out repos created after sync time.
+    pub fn fill_user_repos(&self, path: &str, user: &str) -> Result<()> {
+        if !self.provider.can_list_repos() {
+            return Ok(());
+        }
+        self.locks.with_lock(&format!("user:{}", user), || {
+            if self.index().user_fetched_at(user).is_some_and(|time| !is_expired(time)) {
+                return Ok(())
+            }
+            let repos = match self.provider.list_user_repos(user) {
+                Ok(repos) => repos,
+                // The repos which were listed before are shown until the host can be reached.
+                Err(e) if self.index().user_fetched_at(user).is_some() => {
+                    println!("Unable to list the repos of {} again ({})", user, e);
+                    return Ok(())
+                },
+                Err(e) => return Err(e),
+            };
+            if !repos.is_empty() {
+                fs::create_dir_all(path)?;
+            }
+            for name in repos {
+                // The directories may be left over from an earlier listing.
+                fs::create_dir_all(format!("{}/{}", path, name))?;
+            }
+            self.index().mark_user_fetched(user)
+        })
+    }
+}
+
+fn stored_commit(sha: String) -> Option<String> {
+    if sha == NO_COMMIT {
+        None
+    } else {
+        Some(sha)
+    }
+}
+
+// Returns true if a directory which follows a branch or tag shows. A sha never moves.
+fn follows_ref(revision: &Revision) -> bool {
+    match revision {
+        Revision{end_time: Some(_), ..} => false,
+        Revision{reference: Some(reference), ..} => !is_sha(reference),
+        _ => true,
+    }
+}
+
+// Returns true if reference is a full or abbreviated sha rather than the name of a branch or tag.
+// A branch or tag whose name looks like a sha is taken to be one.
+fn is_sha(reference: &str) -> bool {
+    (7..=40).contains(&reference.len()) && reference.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Returns true if something which was fetched at time has to be fetched again.
+fn is_expired(time: DateTime<Utc>) -> bool {
+    Utc::now().signed_duration_since(time) > chrono::Duration::seconds(REFRESH_SECONDS)
+}
+
+// Removes everything in a directory, keeping the directory itself.
+fn clear_dir(path: &str) -> Result<()> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(time: &str) -> Option<DateTime<Utc>> {
+        Some(DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc))
+    }
+
+    #[test]
+    fn parses_dates_and_times() {
+        assert_eq!(parse_time("2020-06-01"), time("2020-06-01T00:00:00Z"));
+        assert_eq!(parse_time("2020-06-01T12:30:00Z"), time("2020-06-01T12:30:00Z"));
+        assert_eq!(parse_time("2020-06-01T14:30:00+02:00"), time("2020-06-01T12:30:00Z"));
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        assert_eq!(parse_time(""), None);
+        assert_eq!(parse_time("yesterday"), None);
+        assert_eq!(parse_time("2020-13-01"), None);
+        assert_eq!(parse_time("2020-06-01 12:30"), None);
+    }
+
+    #[test]
+    fn recognizes_full_and_short_shas() {
+        assert!(is_sha("0123456789abcdef0123456789abcdef01234567"));
+        assert!(is_sha("c0ffee1"));
+        assert!(is_sha("C0FFEE12"));
+        assert!(!is_sha("c0ffee"));
+        assert!(!is_sha("0123456789abcdef0123456789abcdef012345678"));
+        assert!(!is_sha("main"));
+        assert!(!is_sha("release"));
+    }
+
+    #[test]
+    fn follows_only_branches_and_tags() {
+        assert!(follows_ref(&split_repo_name("repo").1));
+        assert!(follows_ref(&split_repo_name("repo@main").1));
+        assert!(!follows_ref(&split_repo_name("repo@c0ffee1").1));
+        assert!(!follows_ref(&split_repo_name("repo@main@{2020-06-01}").1));
+    }
+
+    #[test]
+    fn splits_plain_repo_name() {
+        let (name, revision) = split_repo_name("repo");
+        assert_eq!(name, "repo");
+        assert_eq!(revision.reference, None);
+        assert_eq!(revision.end_time, None);
+    }
+
+    #[test]
+    fn splits_reference() {
+        let (name, revision) = split_repo_name("repo@v1.0");
+        assert_eq!(name, "repo");
+        assert_eq!(revision.reference, Some("v1.0".to_string()));
+        assert_eq!(revision.end_time, None);
+    }
+
+    #[test]
+    fn decodes_slashes_in_reference() {
+        let (name, revision) = split_repo_name("repo@feature%2Fnew%2Fui");
+        assert_eq!(name, "repo");
+        assert_eq!(revision.reference, Some("feature/new/ui".to_string()));
+    }
+
+    #[test]
+    fn splits_time() {
+        let (name, revision) = split_repo_name("repo@{2020-06-01}");
+        assert_eq!(name, "repo");
+        assert_eq!(revision.reference, None);
+        assert_eq!(revision.end_time, time("2020-06-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn splits_reference_and_time() {
+        let (name, revision) = split_repo_name("repo@release%2F2.x@{2020-06-01T12:30:00Z}");
+        assert_eq!(name, "repo");
+        assert_eq!(revision.reference, Some("release/2.x".to_string()));
+        assert_eq!(revision.end_time, time("2020-06-01T12:30:00Z"));
+    }
+
+    #[test]
+    fn treats_invalid_time_as_reference() {
+        let (name, revision) = split_repo_name("repo@{yesterday}");
+        assert_eq!(name, "repo");
+        assert_eq!(revision.reference, Some("{yesterday}".to_string()));
+        assert_eq!(revision.end_time, None);
+
+        let (_, revision) = split_repo_name("repo@main@{yesterday}");
+        assert_eq!(revision.reference, Some("main@{yesterday}".to_string()));
+        assert_eq!(revision.end_time, None);
+    }
+
+    #[test]
+    fn requires_at_sign_before_time() {
+        let (_, revision) = split_repo_name("repo@main{2020-06-01}");
+        assert_eq!(revision.reference, Some("main{2020-06-01}".to_string()));
+        assert_eq!(revision.end_time, None);
+    }
+}
@@ -0,0 +1,254 @@
+// The metadata of the repos of a host, persisted as a journal so that the cache can be reused
+// after a restart.
+//
+// Every change is appended to the journal as a line of JSON before the files that it describes
+// are written to the cache. A placeholder is therefore always known to be a placeholder, even if
+// the filesystem stopped before its contents were fetched. The journal is replayed and compacted
+// when it is opened, and compacted again whenever it has grown well past its compacted size. Entries from before the owner of each repo was recorded are skipped, so
+// those repos are listed again. A repo directory which is reset, because the branch which it
+// follows has moved, starts over with an empty Repo.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::path::Path;
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::json;
+use serde_json::value::Value;
+use crate::error::{GitFSError, Result};
+use crate::journal;
+use crate::repo_id::{RepoId};
+
+// The journal is compacted once it is this many bytes larger than it was after it was last
+// compacted, and at least twice as large.
+const COMPACT_GROWTH: u64 = 16 * 1024 * 1024;
+
+// A repo at a single revision. Each <repo>@<ref> directory is its own Repo.
+struct Repo {
+    // Maps a directory to a tree sha.
+    tree: HashMap<String, String>,
+    cloned_structures: HashSet<String>,
+    timestamp_to_sha: Option<(DateTime<Utc>, String)>,
+    zero_files: HashSet<String>,
+    // Placeholders for files whose size the provider did not report.
+    unsized_files: HashSet<String>,
+}
+
+pub struct Index {
+    // Maps the owner and directory name (which includes the ref, if any) of a repo to the repo.
+    repos: HashMap<(String, String), Repo>,
+    // Maps a user to the time at which their repos were listed.
+    fetched_users: HashMap<String, DateTime<Utc>>,
+    journal: File,
+    journal_path: String,
+    // The size of the journal, and its size after it was last compacted.
+    journal_size: u64,
+    compacted_size: u64,
+}
+
+impl Index {
+    // Loads the index from the journal at path, creating it if needed.
+    pub fn open(path: &str) -> Result<Index> {
+        fs::create_dir_all(Path::new(path).parent().ok_or(GitFSError::NoneError)?)?;
+        let mut index = Index{
+            repos: HashMap::new(),
+            fetched_users: HashMap::new(),
+            journal: journal::open(path)?,
+            journal_path: path.to_string(),
+            journal_size: 0,
+            compacted_size: 0,
+        };
+        journal::replay(path, "index entry", |entry| index.apply(entry))?;
+        index.compact()?;
+        Ok(index)
+    }
+
+    // Rewrites the journal with only the entries needed to describe the current state.
+    fn compact(&mut self) -> Result<()> {
+        let contents: String = self.snapshot().iter().map(|entry| format!("{}\n", entry)).collect();
+        journal::write_atomic(&self.journal_path, contents.as_bytes())?;
+        self.journal = journal::open(&self.journal_path)?;
+        self.journal_size = contents.len() as u64;
+        self.compacted_size = self.journal_size;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Vec<Value> {
+        let mut entries = Vec::new();
+        for (user, time) in &self.fetched_users {
+            entries.push(json!({"op": "user", "user": user, "time": time.to_rfc3339()}));
+        }
+        for ((owner, name), repo) in &self.repos {
+            if let Some((timestamp, sha)) = &repo.timestamp_to_sha {
+                entries.push(json!({"op": "commit", "owner": owner, "repo": name, "time": timestamp.to_rfc3339(), "sha": sha}));
+            }
+            for (path, sha) in &repo.tree {
+                if repo.zero_files.contains(path) {
+                    let sized = !repo.unsized_files.contains(path);
+                    entries.push(json!({"op": "placeholder", "owner": owner, "repo": name, "path": path, "sha": sha, "sized": sized}));
+                } else {
+                    entries.push(json!({"op": "tree", "owner": owner, "repo": name, "path": path, "sha": sha}));
+                }
+            }
+            for path in &repo.cloned_structures {
+                entries.push(json!({"op": "cloned", "owner": owner, "repo": name, "path": path}));
+            }
+        }
+        entries
+    }
+
+    fn get_repo_or_create(&mut self, owner: &str, repo_name: &str) -> &mut Repo {
+        self.repos.entry((owner.to_string(), repo_name.to_string())).or_insert_with(|| Repo{
+            tree: HashMap::new(),
+            cloned_structures: HashSet::new(),
+            timestamp_to_sha: None,
+            zero_files: HashSet::new(),
+            unsized_files: HashSet::new(),
+        })
+    }
+
+    // Applies a journal entry to the in-memory state. Returns None if the entry is invalid.
+    fn apply(&mut self, entry: &Value) -> Option<()> {
+        let op = entry["op"].as_str()?;
+        if op == "user" {
+            // Users which were listed before the time was recorded are listed again.
+            let time = entry["time"].as_str().and_then(|t| DateTime::parse_from_rfc3339(t).ok());
+            let time = time.map_or(Utc.timestamp_opt(0, 0).single()?, |t| t.with_timezone(&Utc));
+            self.fetched_users.insert(entry["user"].as_str()?.to_string(), time);
+            return Some(());
+        }
+        if op == "reset" {
+            self.repos.remove(&(entry["owner"].as_str()?.to_string(), entry["repo"].as_str()?.to_string()));
+            return Some(());
+        }
+        let repo = self.get_repo_or_create(entry["owner"].as_str()?, entry["repo"].as_str()?);
+        match op {
+            "commit" => {
+                let timestamp = DateTime::parse_from_rfc3339(entry["time"].as_str()?).ok()?;
+                repo.timestamp_to_sha = Some((timestamp.with_timezone(&Utc), entry["sha"].as_str()?.to_string()));
+            },
+            "tree" => {
+                repo.tree.insert(entry["path"].as_str()?.to_string(), entry["sha"].as_str()?.to_string());
+            },
+            "placeholder" => {
+                let path = entry["path"].as_str()?.to_string();
+                repo.tree.insert(path.clone(), entry["sha"].as_str()?.to_string());
+                if !entry["sized"].as_bool()? {
+                    repo.unsized_files.insert(path.clone());
+                }
+                repo.zero_files.insert(path);
+            },
+            "evicted" => {
+                let path = entry["path"].as_str()?;
+                repo.cloned_structures.remove(path);
+                repo.zero_files.insert(path.to_string());
+            },
+            "expired" => {
+                repo.cloned_structures.remove(entry["path"].as_str()?);
+            },
+            "cloned" => {
+                let path = entry["path"].as_str()?;
+                repo.zero_files.remove(path);
+                repo.unsized_files.remove(path);
+                repo.cloned_structures.insert(path.to_string());
+            },
+            _ => return None,
+        }
+        Some(())
+    }
+
+    // Applies an entry and appends it to the journal.
+    fn record(&mut self, entry: Value) -> Result<()> {
+        self.apply(&entry).ok_or(GitFSError::NoneError)?;
+        journal::append(&mut self.journal, &entry)?;
+        self.journal_size += entry.to_string().len() as u64 + 1;
+        if self.journal_size > (self.compacted_size + COMPACT_GROWTH).max(2 * self.compacted_size) {
+            // The entry has already been applied, so the compacted journal includes it.
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn get_repo(&self, repo: &RepoId) -> Option<&Repo> {
+        self.repos.get(&(repo.owner.clone(), repo.dir_name.clone()))
+    }
+
+    pub fn tree_sha(&self, repo: &RepoId, path: &str) -> Option<String> {
+        self.get_repo(repo)?.tree.get(path).cloned()
+    }
+
+    // Returns a path whose tree or file has the given sha.
+    pub fn path_of(&self, repo: &RepoId, sha: &str) -> Option<String> {
+        self.get_repo(repo)?.tree.iter().find(|(_, s)| *s == sha).map(|(path, _)| path.clone())
+    }
+
+    // Returns the commit which a repo directory shows, if it has been resolved.
+    pub fn commit(&self, repo: &RepoId) -> Option<String> {
+        Some(self.get_repo(repo)?.timestamp_to_sha.as_ref()?.1.clone())
+    }
+
+    // Returns the time at which the commit of a repo directory was resolved, which is the time
+    // that it is shown at if it was resolved for a point in time.
+    pub fn commit_time(&self, repo: &RepoId) -> Option<DateTime<Utc>> {
+        Some(self.get_repo(repo)?.timestamp_to_sha.as_ref()?.0)
+    }
+
+    pub fn is_cloned(&self, repo: &RepoId, path: &str) -> bool {
+        self.get_repo(repo).is_some_and(|r| r.cloned_structures.contains(path))
+    }
+
+    // Returns true if path is a placeholder whose contents have not been fetched.
+    pub fn is_zero(&self, repo: &RepoId, path: &str) -> bool {
+        self.get_repo(repo).is_some_and(|r| r.zero_files.contains(path))
+    }
+
+    // Returns true if the placeholder for path does not have the size of the real file.
+    pub fn is_unsized(&self, repo: &RepoId, path: &str) -> bool {
+        self.get_repo(repo).is_some_and(|r| r.unsized_files.contains(path))
+    }
+
+    // Returns the time at which the repos of a user were listed, if they have been.
+    pub fn user_fetched_at(&self, user: &str) -> Option<DateTime<Utc>> {
+        self.fetched_users.get(user).cloned()
+    }
+
+    pub fn set_commit(&mut self, repo: &RepoId, timestamp: DateTime<Utc>, sha: &str) -> Result<()> {
+        self.record(json!({"op": "commit", "owner": repo.owner, "repo": repo.dir_name, "time": timestamp.to_rfc3339(), "sha": sha}))
+    }
+
+    pub fn add_tree(&mut self, repo: &RepoId, path: &str, sha: &str) -> Result<()> {
+        self.record(json!({"op": "tree", "owner": repo.owner, "repo": repo.dir_name, "path": path, "sha": sha}))
+    }
+
+    // Records a placeholder for a file. This must be done before the placeholder is written.
+    pub fn add_placeholder(&mut self, repo: &RepoId, path: &str, sha: &str, sized: bool) -> Result<()> {
+        self.record(json!({"op": "placeholder", "owner": repo.owner, "repo": repo.dir_name, "path": path, "sha": sha, "sized": sized}))
+    }
+
+    // Records that a directory has been listed or that the real contents of a file are in the
+    // cache. This must be done after the files have been written.
+    pub fn mark_cloned(&mut self, repo: &RepoId, path: &str) -> Result<()> {
+        self.record(json!({"op": "cloned", "owner": repo.owner, "repo": repo.dir_name, "path": path}))
+    }
+
+    // Records that a file has been turned back into a placeholder. This must be done before the
+    // file is replaced.
+    pub fn mark_evicted(&mut self, repo: &RepoId, path: &str) -> Result<()> {
+        self.record(json!({"op": "evicted", "owner": repo.owner, "repo": repo.dir_name, "path": path}))
+    }
+
+    // Records that a directory has to be listed again. This must be done before the files in it
+    // are removed.
+    pub fn mark_expired(&mut self, repo: &RepoId, path: &str) -> Result<()> {
+        self.record(json!({"op": "expired", "owner": repo.owner, "repo": repo.dir_name, "path": path}))
+    }
+
+    // Forgets everything about a repo directory, so that it is listed again from scratch. This
+    // must be done before its files are removed.
+    pub fn reset(&mut self, repo: &RepoId) -> Result<()> {
+        self.record(json!({"op": "reset", "owner": repo.owner, "repo": repo.dir_name}))
+    }
+
+    pub fn mark_user_fetched(&mut self, user: &str) -> Result<()> {
+        self.record(json!({"op": "user", "user": user, "time": Utc::now().to_rfc3339()}))
+    }
+}
@@ -0,0 +1,51 @@
+// Reads and writes the files which hold the state of the filesystem. A journal has a line of JSON
+// for each change, which is appended before the change is made and replayed when the state is
+// loaded. Other files are replaced as a whole, so that neither a reader nor a restart ever sees
+// them half written.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::thread;
+use serde_json::value::Value;
+use crate::error::{Result};
+
+// Calls apply with each entry of the journal at path, if there is one. Entries which are not
+// valid JSON or which apply returns None for are skipped and logged as invalid <kind>s.
+pub fn replay<F>(path: &str, kind: &str, mut apply: F) -> Result<()>
+    where F: FnMut(&Value) -> Option<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        // The last line may be incomplete if the filesystem stopped while writing it.
+        let applied = serde_json::from_str(&line).ok().and_then(|entry| apply(&entry));
+        if applied.is_none() {
+            eprintln!("Skipping invalid {}: {}", kind, line);
+        }
+    }
+    Ok(())
+}
+
+// Opens the journal at path for appending, creating it if needed.
+pub fn open(path: &str) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+pub fn append(journal: &mut File, entry: &Value) -> Result<()> {
+    journal.write_all(format!("{}\n", entry).as_bytes())?;
+    Ok(())
+}
+
+// Replaces the file at path with contents. Several threads may replace the same file at once, so
+// each writes its own temporary file.
+pub fn write_atomic(path: &str, contents: &[u8]) -> Result<()> {
+    let tmp_path = format!("{}.{:?}.tmp", path, thread::current().id());
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(contents)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,110 @@
+// Locks which are identified by a key, such as a path or a sha. Work on the same key is done by
+// one thread at a time, so threads which need the same directory listing or download wait for the
+// first one instead of repeating it. Work on different keys runs in parallel.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+pub struct KeyedLocks {
+    locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl KeyedLocks {
+    pub fn new() -> KeyedLocks {
+        KeyedLocks{
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Runs f while holding the lock for key. As f may have waited for another thread which did
+    // the same work, it should check whether the work is still needed.
+    pub fn with_lock<T, F>(&self, key: &str, f: F) -> T
+        where F: FnOnce() -> T {
+        let lock = self.locks.lock().unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone();
+        let result = {
+            // A panic in another thread does not leave anything behind which needs to be undone.
+            let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+            f()
+        };
+        let mut locks = self.locks.lock().unwrap();
+        // If only the map and this thread have the lock then nobody is waiting for it.
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(key);
+        }
+        result
+    }
+}
+
+// Locks which are identified by a key and which any number of threads can share, unless one
+// thread holds the lock alone. A thread which shares a lock can share it again in a nested call,
+// as sharing only waits for a thread which holds the lock alone, not for one which is waiting to.
+pub struct SharedLocks {
+    // Maps a key to the number of threads which share its lock, or to None if one thread holds it
+    // alone.
+    holders: Mutex<HashMap<String, Option<usize>>>,
+    released: Condvar,
+}
+
+impl SharedLocks {
+    pub fn new() -> SharedLocks {
+        SharedLocks{
+            holders: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    // Runs f while sharing the lock for key with other threads.
+    pub fn with_shared_lock<T, F>(&self, key: &str, f: F) -> T
+        where F: FnOnce() -> T {
+        {
+            let mut holders = self.holders.lock().unwrap();
+            while holders.get(key) == Some(&None) {
+                holders = self.released.wait(holders).unwrap();
+            }
+            let count = holders.entry(key.to_string()).or_insert(Some(0));
+            *count = count.map(|count| count + 1);
+        }
+        let _release = Release{locks: self, key: key};
+        f()
+    }
+
+    // Runs f while no other thread holds the lock for key. Threads which share the lock keep it
+    // until they are done, so f may have to wait for them for a while.
+    pub fn with_exclusive_lock<T, F>(&self, key: &str, f: F) -> T
+        where F: FnOnce() -> T {
+        {
+            let mut holders = self.holders.lock().unwrap();
+            while holders.contains_key(key) {
+                holders = self.released.wait(holders).unwrap();
+            }
+            holders.insert(key.to_string(), None);
+        }
+        let _release = Release{locks: self, key: key};
+        f()
+    }
+}
+
+// Releases a lock of SharedLocks when it is dropped, even if f panicked.
+struct Release<'a> {
+    locks: &'a SharedLocks,
+    key: &'a str,
+}
+
+impl Drop for Release<'_> {
+    fn drop(&mut self) {
+        let mut holders = self.locks.holders.lock().unwrap_or_else(|e| e.into_inner());
+        let count = match holders.get(self.key) {
+            Some(Some(count)) => count - 1,
+            _ => 0,
+        };
+        if count == 0 {
+            holders.remove(self.key);
+            self.locks.released.notify_all();
+        } else {
+            holders.insert(self.key.to_string(), Some(count));
+        }
+    }
+}
@@ -1,7 +1,8 @@
-#![feature(try_trait)]
+// Structs are initialized with the field names spelled out throughout.
+#![allow(clippy::redundant_field_names)]
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::fs;
+use std::time::Duration;
 
 #[macro_use]
 extern crate log;
@@ -12,8 +13,22 @@ extern crate rouille;
 mod libc_extras;
 mod libc_wrappers;
 mod filesystem;
+mod blob_store;
 mod git;
+mod git_dir;
 mod github;
+mod host;
+mod index;
+mod journal;
+mod locks;
+mod network;
+mod overlay;
+mod prefetch;
+mod provider;
+mod repo_id;
+mod response_cache;
+mod retry;
+mod smart_http;
 mod oauth;
 mod error;
 
@@ -33,26 +48,114 @@ impl log::Log for ConsoleLogger {
 
 static LOGGER: ConsoleLogger = ConsoleLogger;
 
+// The number of threads which serve FUSE requests.
+const FUSE_THREADS: usize = 16;
+
 fn main() {
     log::set_logger(&LOGGER).unwrap();
     log::set_max_level(log::LevelFilter::Debug);
-    let args: Vec<OsString> = env::args_os().collect();
+    let mut args: Vec<OsString> = env::args_os().collect();
 
-    if args.len() != 3 {
-        eprintln!("usage: {} <cache_dir> <mountpoint>", &env::args().next().unwrap());
-        ::std::process::exit(1);
+    // --at <time> shows every repo as it was at that time. <time> is a date or RFC 3339 time.
+    // --cache-size <size> limits the size of the cached file contents, e.g. 512M or 10G.
+    // --prefetch-depth <n> lists n levels of subdirectories in the background when a directory is
+    // listed, and --prefetch-size <size> also fetches the files of up to that size in them.
+    // --github-api <rest|graphql> selects the Github API, which is found at --github-url <url>.
+    // --retries <n> makes each request up to n times if it fails for a temporary reason, and
+    // --timeout <seconds> fails a request which has not finished in time.
+    // --offline only serves what is already in the cache.
+    // --http-host <host> reaches the git server at <host> over plain HTTP. It may be repeated.
+    let mut options = filesystem::Options::default();
+    while args.len() > 2 {
+        if args[1] == "--offline" {
+            options.offline = true;
+            args.remove(1);
+            continue;
+        }
+        let value = args[2].to_str().unwrap_or("");
+        if args[1] == "--at" {
+            options.timestamp = host::parse_time(value);
+            if options.timestamp.is_none() {
+                eprintln!("invalid time: {:?}", args[2]);
+                ::std::process::exit(1);
+            }
+        } else if args[1] == "--cache-size" {
+            options.cache_limit = blob_store::parse_size(value);
+            if options.cache_limit.is_none() {
+                eprintln!("invalid size: {:?}", args[2]);
+                ::std::process::exit(1);
+            }
+        } else if args[1] == "--prefetch-depth" {
+            options.prefetch_depth = match value.parse() {
+                Ok(depth) => depth,
+                Err(_) => {
+                    eprintln!("invalid depth: {:?}", args[2]);
+                    ::std::process::exit(1);
+                },
+            };
+        } else if args[1] == "--prefetch-size" {
+            options.prefetch_size = match blob_store::parse_size(value) {
+                Some(size) => size,
+                None => {
+                    eprintln!("invalid size: {:?}", args[2]);
+                    ::std::process::exit(1);
+                },
+            };
+        } else if args[1] == "--github-api" {
+            options.github_graphql = match value {
+                "rest" => false,
+                "graphql" => true,
+                _ => {
+                    eprintln!("invalid Github API: {:?}", args[2]);
+                    ::std::process::exit(1);
+                },
+            };
+        } else if args[1] == "--github-url" {
+            options.github_url = value.to_string();
+        } else if args[1] == "--http-host" {
+            options.http_hosts.push(value.to_string());
+        } else if args[1] == "--retries" {
+            options.retry.attempts = match value.parse::<u32>() {
+                Ok(retries) => retries + 1,
+                Err(_) => {
+                    eprintln!("invalid number of retries: {:?}", args[2]);
+                    ::std::process::exit(1);
+                },
+            };
+        } else if args[1] == "--timeout" {
+            options.retry.timeout = match value.parse() {
+                Ok(seconds) => Duration::from_secs(seconds),
+                Err(_) => {
+                    eprintln!("invalid timeout: {:?}", args[2]);
+                    ::std::process::exit(1);
+                },
+            };
+        } else {
+            break;
+        }
+        args.drain(1..3);
     }
 
-    // Setup the github directory so that it is visible at startup.
-    let github_repo = format!("{}/repos/github.com", args[1].to_str().unwrap());
-    if let Err(e) = fs::create_dir_all(github_repo) {
-        eprintln!("unable to create cache directory: {}", e);
+    if args.len() != 3 {
+        eprintln!("usage: {} [--at <time>] [--cache-size <size>] [--prefetch-depth <n>] [--prefetch-size <size>] [--github-api <rest|graphql>] [--github-url <url>] [--retries <n>] [--timeout <seconds>] [--offline] [--http-host <host>] <cache_dir> <mountpoint>", &env::args().next().unwrap());
         ::std::process::exit(1);
     }
-    
-    // Get the cache directory and the oauth token and init the filesystem.
-    let filesystem = filesystem::PassthroughFS::new(oauth::get_token().unwrap(), args[1].to_str().unwrap().to_string());
+
+    // Get the cache directory and the oauth token and init the filesystem. There is no way to
+    // authorize while offline, and the cache can be read without a token.
+    let token = if options.offline {
+        oauth::stored_token().unwrap_or_default()
+    } else {
+        oauth::get_token().unwrap()
+    };
+    let filesystem = match filesystem::PassthroughFS::new(token, args[1].to_str().unwrap().to_string(), options) {
+        Ok(filesystem) => filesystem,
+        Err(e) => {
+            eprintln!("unable to load cache: {}", e);
+            ::std::process::exit(1);
+        },
+    };
 
     let fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
-    fuse_mt::mount(fuse_mt::FuseMT::new(filesystem, 1), &args[2], &fuse_args).unwrap();
+    fuse_mt::mount(fuse_mt::FuseMT::new(filesystem, FUSE_THREADS), &args[2], &fuse_args).unwrap();
 }
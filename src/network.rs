@@ -0,0 +1,97 @@
+// Tracks which hosts can be reached. In offline mode no requests are made at all, and a host which
+// could not be reached is not tried again for a while, so that the filesystem can serve what is
+// in the cache instead of waiting for every request to time out.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use reqwest::Url;
+use crate::error::{GitFSError, Result};
+use crate::libc_extras::libc;
+
+// How long requests to a host which could not be reached fail without being made.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Network {
+    offline: AtomicBool,
+    // Maps a host which could not be reached to the time at which it is tried again.
+    unreachable: Mutex<HashMap<String, Instant>>,
+}
+
+impl Network {
+    pub fn new() -> Network {
+        Network{
+            offline: AtomicBool::new(false),
+            unreachable: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::Relaxed);
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+
+    // Returns an error if a request to url should not be made.
+    pub fn check(&self, url: &str) -> Result<()> {
+        if self.is_offline() {
+            return Err(unreachable_error());
+        }
+        let host = host_of(url);
+        let mut unreachable = self.unreachable.lock().unwrap();
+        match unreachable.get(&host) {
+            Some(retry_at) if Instant::now() < *retry_at => Err(unreachable_error()),
+            Some(_) => {
+                unreachable.remove(&host);
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    // Records the result of a request to url. Returns the error which the request should fail
+    // with, which is EHOSTUNREACH if the host could not be reached.
+    pub fn report(&self, url: &str, e: GitFSError) -> GitFSError {
+        if !is_unreachable(&e) {
+            return e;
+        }
+        let host = host_of(url);
+        eprintln!("{} can not be reached ({}), only the cache is used for {:?}", host, e, RECHECK_INTERVAL);
+        self.unreachable.lock().unwrap().insert(host, Instant::now() + RECHECK_INTERVAL);
+        unreachable_error()
+    }
+
+    // Returns the hosts which are not being tried and the number of seconds until they are.
+    pub fn unreachable_hosts(&self) -> Vec<(String, u64)> {
+        let now = Instant::now();
+        let mut hosts: Vec<(String, u64)> = self.unreachable.lock().unwrap().iter()
+            .filter(|(_, retry_at)| now < **retry_at)
+            .map(|(host, retry_at)| (host.clone(), (*retry_at - now).as_secs()))
+            .collect();
+        hosts.sort();
+        hosts
+    }
+}
+
+fn host_of(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(url) => url.host_str().unwrap_or("").to_string(),
+        Err(_) => url.to_string(),
+    }
+}
+
+fn unreachable_error() -> GitFSError {
+    GitFSError::new("Host is unreachable", libc::EHOSTUNREACH)
+}
+
+// A failed connection or a request which timed out means that the host can not be reached. Any
+// response at all, even an error, means that it can.
+fn is_unreachable(e: &GitFSError) -> bool {
+    match e {
+        GitFSError::ReqwestError(e) => e.status().is_none() && (e.is_connect() || e.is_timeout()),
+        _ => false,
+    }
+}
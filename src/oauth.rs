@@ -1,7 +1,3 @@
-use dirs;
-use rouille;
-use webbrowser;
-use reqwest;
 use std::fs;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
@@ -19,7 +15,7 @@ fn get_redirect_code() -> String {
                 println!("Sending code: {}", &code);
                 sender_wrapped.lock().unwrap().send(code).unwrap();
                 println!("Code sent");
-                rouille::Response::text(format!("You are now authenticated and can use Virtual Git Filesystem."))
+                rouille::Response::text("You are now authenticated and can use Virtual Git Filesystem.".to_string())
             },
 
             // The code block is called if none of the other blocks matches the request.
@@ -29,12 +25,9 @@ fn get_redirect_code() -> String {
     }).unwrap();
     launch_webpage();
     loop {
-        match code_receiver.try_recv() {
-            Ok(code) => {
-                println!("Got code: {}", &code);
-                return code;
-            },
-            Err(_) => {},
+        if let Ok(code) = code_receiver.try_recv() {
+            println!("Got code: {}", &code);
+            return code;
         }
         server.poll();
     }
@@ -50,16 +43,25 @@ fn exchange_for_token(code: String) -> String {
 
 fn launch_webpage() {
     let url = format!("https://github.com/login/oauth/authorize?client_id={}&redirect_uri={}&scope={}&state={}", "d8dfe8c41abaf9d989a6", "http://localhost:35918", "repo", "changethis");
-    if !webbrowser::open(&url).is_ok() {
+    if webbrowser::open(&url).is_err() {
         eprintln!("Unable to open web browser for OAuth exchange. Some features will be limited.")
     }
 }
 
-pub fn get_token() -> Option<String> {
+fn token_file() -> String {
     let cache_dir = dirs::cache_dir().unwrap();
     let credential_dir = format!("{}/gitfs/.credentials", cache_dir.to_str().unwrap());
     std::fs::create_dir_all(&credential_dir).unwrap();
-    let token_file = format!("{}/.token", credential_dir);
+    format!("{}/.token", credential_dir)
+}
+
+// Returns the token from a previous OAuth flow, if there was one.
+pub fn stored_token() -> Option<String> {
+    std::fs::read_to_string(token_file()).ok()
+}
+
+pub fn get_token() -> Option<String> {
+    let token_file = token_file();
     match std::fs::read_to_string(&token_file) {
         Ok(token) => {
             return Some(token);
@@ -0,0 +1,311 @@
+// A copy-on-write layer over the working tree of each repo directory. The cache only ever holds
+// what the remote repo has. A file which is created or modified is kept in an upper layer of its
+// repo instead, a file of the cache is copied there the first time it is modified, and a deleted
+// file of the cache is recorded as a whiteout which hides it. The changes to a repo are listed in
+// its .vg/status file.
+//
+// The whiteouts of each repo are kept in a journal next to its upper layer, in the same way as
+// the index.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use git2::{ObjectType, Oid};
+use serde_json::json;
+use walkdir::WalkDir;
+use crate::error::{GitFSError, Result};
+use crate::journal;
+use crate::libc_extras::libc;
+use crate::locks::{KeyedLocks};
+use crate::repo_id::{RepoId};
+
+// The directory at the root of every repo which describes the changes to it.
+pub const CHANGES_DIR: &str = ".vg";
+// The file in CHANGES_DIR which lists the changed files.
+pub const STATUS_FILE: &str = "status";
+
+pub struct Overlay {
+    dir: String,
+    // Maps a repo to the paths in it which were deleted. The whiteouts of a repo are loaded the
+    // first time that it is used.
+    deleted: Mutex<HashMap<RepoId, BTreeSet<String>>>,
+    // Held while a file is copied to the upper layer, so that it is only copied once.
+    locks: KeyedLocks,
+}
+
+impl Overlay {
+    pub fn new(cache_dir: &str) -> Overlay {
+        Overlay{
+            dir: format!("{}/overlay", cache_dir),
+            deleted: Mutex::new(HashMap::new()),
+            locks: KeyedLocks::new(),
+        }
+    }
+
+    // Returns the path of a file of a repo in its upper layer, whether or not it is there.
+    pub fn upper_path(&self, repo: &RepoId, path: &str) -> String {
+        format!("{}/{}/files/{}", self.dir, repo, path)
+    }
+
+    fn journal_path(&self, repo: &RepoId) -> String {
+        format!("{}/{}/deleted", self.dir, repo)
+    }
+
+    // Runs f with the deleted paths of a repo, loading them from the journal if needed.
+    fn with_deleted<T, F>(&self, repo: &RepoId, f: F) -> Result<T>
+        where F: FnOnce(&mut BTreeSet<String>) -> Result<T> {
+        let mut deleted = self.deleted.lock().unwrap();
+        if !deleted.contains_key(repo) {
+            deleted.insert(repo.clone(), self.load_deleted(repo)?);
+        }
+        f(deleted.get_mut(repo).ok_or(GitFSError::NoneError)?)
+    }
+
+    fn load_deleted(&self, repo: &RepoId) -> Result<BTreeSet<String>> {
+        let mut deleted = BTreeSet::new();
+        journal::replay(&self.journal_path(repo), "whiteout", |entry| {
+            let path = entry["path"].as_str()?.to_string();
+            match entry["op"].as_str()? {
+                "delete" => { deleted.insert(path); },
+                "restore" => { deleted.remove(&path); },
+                _ => return None,
+            }
+            Some(())
+        })?;
+        Ok(deleted)
+    }
+
+    // Records a change to the deleted paths of a repo in its journal.
+    fn record(&self, repo: &RepoId, op: &str, path: &str) -> Result<()> {
+        let journal_path = self.journal_path(repo);
+        fs::create_dir_all(Path::new(&journal_path).parent().ok_or(GitFSError::NoneError)?)?;
+        journal::append(&mut journal::open(&journal_path)?, &json!({"op": op, "path": path}))
+    }
+
+    // Returns true if path or one of its parents was deleted.
+    pub fn is_deleted(&self, repo: &RepoId, path: &str) -> Result<bool> {
+        self.with_deleted(repo, |deleted| {
+            Ok(Path::new(path).ancestors().any(|p| deleted.contains(p.to_str().unwrap_or(""))))
+        })
+    }
+
+    // Returns the names of the deleted entries of a directory.
+    pub fn deleted_names(&self, repo: &RepoId, dir: &str) -> Result<HashSet<String>> {
+        self.with_deleted(repo, |deleted| {
+            Ok(deleted.iter()
+                .filter(|path| Path::new(path).parent() == Some(Path::new(dir)))
+                .filter_map(|path| Some(Path::new(path).file_name()?.to_str()?.to_string()))
+                .collect())
+        })
+    }
+
+    // Hides a file or directory of the cache.
+    pub fn mark_deleted(&self, repo: &RepoId, path: &str) -> Result<()> {
+        self.with_deleted(repo, |deleted| {
+            if deleted.insert(path.to_string()) {
+                self.record(repo, "delete", path)?;
+            }
+            Ok(())
+        })
+    }
+
+    // Copies a file of the cache at lower_path, whose contents have been fetched, to the upper
+    // layer before it is modified. Returns its path in the upper layer.
+    pub fn copy_up(&self, repo: &RepoId, path: &str, lower_path: &str) -> Result<String> {
+        let upper_path = self.upper_path(repo, path);
+        if lower_path == upper_path {
+            return Ok(upper_path);
+        }
+        self.locks.with_lock(&upper_path, || {
+            if fs::symlink_metadata(&upper_path).is_ok() {
+                return Ok(upper_path.clone());
+            }
+            fs::create_dir_all(Path::new(&upper_path).parent().ok_or(GitFSError::NoneError)?)?;
+            // Copy to a temporary path so that a partial copy is never in the upper layer.
+            let tmp_path = format!("{}.{:?}.tmp", upper_path, thread::current().id());
+            if fs::symlink_metadata(lower_path)?.file_type().is_symlink() {
+                symlink(fs::read_link(lower_path)?, &tmp_path)?;
+            } else {
+                fs::copy(lower_path, &tmp_path)?;
+            }
+            fs::rename(&tmp_path, &upper_path)?;
+            Ok(upper_path.clone())
+        })
+    }
+
+    // Returns the path in the upper layer at which a new file or directory is created, after
+    // creating its parents there. A deleted file of the cache at the same path stays hidden.
+    pub fn create_path(&self, repo: &RepoId, path: &str) -> Result<String> {
+        let upper_path = self.upper_path(repo, path);
+        fs::create_dir_all(Path::new(&upper_path).parent().ok_or(GitFSError::NoneError)?)?;
+        self.with_deleted(repo, |deleted| {
+            // The children of a deleted directory were deleted before it, so they stay hidden
+            // when it is created again.
+            if deleted.remove(path) {
+                self.record(repo, "restore", path)?;
+            }
+            Ok(())
+        })?;
+        Ok(upper_path)
+    }
+
+    // Removes a file or an empty directory from the working tree of a repo. lower_path is its
+    // path in the cache, which may not exist.
+    pub fn remove(&self, repo: &RepoId, path: &str, lower_path: &str, is_dir: bool) -> Result<()> {
+        let upper_path = self.upper_path(repo, path);
+        let in_upper = fs::symlink_metadata(&upper_path).is_ok();
+        let in_lower = !self.is_deleted(repo, path)? && fs::symlink_metadata(lower_path).is_ok();
+        if !in_upper && !in_lower {
+            return Err(GitFSError::new("Not Found", libc::ENOENT));
+        }
+        if is_dir {
+            let deleted = self.deleted_names(repo, path)?;
+            let is_empty = (!in_upper || fs::read_dir(&upper_path)?.next().is_none())
+                && (!in_lower || fs::read_dir(lower_path)?.all(|entry| {
+                    entry.ok().and_then(|e| e.file_name().into_string().ok()).is_some_and(|name| deleted.contains(&name))
+                }));
+            if !is_empty {
+                return Err(GitFSError::new("Directory not empty", libc::ENOTEMPTY));
+            }
+            if in_upper {
+                fs::remove_dir(&upper_path)?;
+            }
+        } else if in_upper {
+            fs::remove_file(&upper_path)?;
+        }
+        if in_lower {
+            self.mark_deleted(repo, path)?;
+        }
+        Ok(())
+    }
+
+    // Writes the list of files which were added (A), modified (M) or deleted (D) in a repo to
+    // status_path. repo_path is the repo directory in the cache. lower_sha returns the sha of the
+    // blob of a file of the cache, if it is known. A file which was copied to the upper layer is
+    // only modified if its contents differ from that blob.
+    pub fn write_status<F>(&self, repo: &RepoId, repo_path: &str, status_path: &str, lower_sha: F) -> Result<()>
+        where F: Fn(&str) -> Option<String> {
+        let mut changes = Vec::new();
+        let upper_dir = self.upper_path(repo, "");
+        for entry in WalkDir::new(&upper_dir).min_depth(1) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                // Nothing has been written to the repo.
+                Err(_) => break,
+            };
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let path = entry.path().strip_prefix(&upper_dir).unwrap_or(entry.path()).to_str().ok_or(GitFSError::NoneError)?.to_string();
+            let lower_path = format!("{}/{}", repo_path, path);
+            if fs::symlink_metadata(&lower_path).is_err() {
+                changes.push((path, "A"));
+            } else if is_modified(&entry.path().to_string_lossy(), &lower_path, lower_sha(&path).as_deref())? {
+                changes.push((path, "M"));
+            }
+        }
+        self.with_deleted(repo, |deleted| {
+            for path in deleted.iter() {
+                // Deleted directories are shown by their files.
+                let lower_path = format!("{}/{}", repo_path, path);
+                if !Path::new(&lower_path).is_dir() && fs::symlink_metadata(&lower_path).is_ok() {
+                    changes.push((path.clone(), "D"));
+                }
+            }
+            Ok(())
+        })?;
+        changes.sort();
+        let status: String = changes.iter().map(|(path, change)| format!("{} {}\n", change, path)).collect();
+        journal::write_atomic(status_path, status.as_bytes())
+    }
+}
+
+// Returns true if the file at upper_path differs from the file of the cache at lower_path, whose
+// blob has the sha lower_sha if it is known. The cache may only hold a placeholder for the file,
+// which has the size of the blob but not its contents, or no size at all.
+fn is_modified(upper_path: &str, lower_path: &str, lower_sha: Option<&str>) -> Result<bool> {
+    let upper = fs::symlink_metadata(upper_path)?;
+    let lower = fs::symlink_metadata(lower_path)?;
+    if upper.file_type().is_file() && lower.file_type().is_file() && lower.len() != 0 && upper.len() != lower.len() {
+        return Ok(true);
+    }
+    let lower_sha = match lower_sha {
+        Some(sha) => Oid::from_str(sha)?,
+        None => blob_sha(lower_path)?,
+    };
+    Ok(blob_sha(upper_path)? != lower_sha)
+}
+
+// Returns the sha which the file or symlink at path has as a git blob.
+fn blob_sha(path: &str) -> Result<Oid> {
+    if fs::symlink_metadata(path)?.file_type().is_symlink() {
+        return Ok(Oid::hash_object(ObjectType::Blob, fs::read_link(path)?.as_os_str().as_bytes())?);
+    }
+    Ok(Oid::hash_file(ObjectType::Blob, path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn repo() -> RepoId {
+        RepoId::new("github.com", "octocat", "hello")
+    }
+
+    #[test]
+    fn replays_whiteouts() {
+        let dir = TempDir::new("vg").unwrap();
+        let cache_dir = dir.path().to_str().unwrap();
+        let overlay = Overlay::new(cache_dir);
+        overlay.mark_deleted(&repo(), "README").unwrap();
+        overlay.mark_deleted(&repo(), "src/main.rs").unwrap();
+        overlay.mark_deleted(&repo(), "src").unwrap();
+        overlay.create_path(&repo(), "README").unwrap();
+        // A line which was only partly written when the filesystem stopped.
+        let mut journal = journal::open(&overlay.journal_path(&repo())).unwrap();
+        std::io::Write::write_all(&mut journal, b"{\"op\": \"dele").unwrap();
+
+        let overlay = Overlay::new(cache_dir);
+        assert!(!overlay.is_deleted(&repo(), "README").unwrap());
+        assert!(overlay.is_deleted(&repo(), "src").unwrap());
+        assert!(overlay.is_deleted(&repo(), "src/lib.rs").unwrap());
+        assert_eq!(overlay.deleted_names(&repo(), "src").unwrap(), ["main.rs".to_string()].iter().cloned().collect());
+        assert!(!overlay.is_deleted(&RepoId::new("github.com", "octocat", "hello@v1"), "src").unwrap());
+    }
+
+    #[test]
+    fn lists_changes_in_status() {
+        let dir = TempDir::new("vg").unwrap();
+        let overlay = Overlay::new(dir.path().to_str().unwrap());
+        let repo_path = dir.path().join("repo");
+        fs::create_dir_all(repo_path.join("src")).unwrap();
+        for name in &["same", "edited", "resized", "gone", "src/main.rs"] {
+            fs::write(repo_path.join(name), b"hello\n").unwrap();
+        }
+        // A placeholder of a file whose contents were evicted from the cache.
+        fs::write(repo_path.join("evicted"), b"\0\0\0\0\0\0").unwrap();
+        let lower_path = |name: &str| repo_path.join(name).to_str().unwrap().to_string();
+        let hello_sha = Oid::hash_object(ObjectType::Blob, b"hello\n").unwrap().to_string();
+        let lower_sha = |path: &str| Some(hello_sha.clone()).filter(|_| path != "resized");
+
+        let copy_up = |name: &str, contents: &[u8]| {
+            fs::write(overlay.copy_up(&repo(), name, &lower_path(name)).unwrap(), contents).unwrap();
+        };
+        copy_up("same", b"hello\n");
+        copy_up("edited", b"HELLO\n");
+        copy_up("resized", b"hello world\n");
+        copy_up("evicted", b"hello\n");
+        fs::write(overlay.create_path(&repo(), "src/new.rs").unwrap(), b"new\n").unwrap();
+        overlay.remove(&repo(), "gone", &lower_path("gone"), false).unwrap();
+        overlay.remove(&repo(), "src/main.rs", &lower_path("src/main.rs"), false).unwrap();
+
+        let status_path = dir.path().join("status");
+        overlay.write_status(&repo(), &lower_path(""), status_path.to_str().unwrap(), lower_sha).unwrap();
+        assert_eq!(fs::read_to_string(&status_path).unwrap(), "M edited\nD gone\nM resized\nD src/main.rs\nA src/new.rs\n");
+    }
+}
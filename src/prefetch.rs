@@ -0,0 +1,118 @@
+// Lists the subdirectories (and fetches the small files) of a directory in the background after it
+// has been listed, so that walking a repo does not wait for one request per directory.
+
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use chrono::{DateTime, Utc};
+use crate::host::{HostFS};
+use crate::repo_id::{RepoId};
+
+// The number of threads which prefetch. These are separate from the FUSE threads.
+const PREFETCH_THREADS: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// A directory whose children should be prefetched.
+#[derive(Clone)]
+pub struct PrefetchDir {
+    pub repo: RepoId,
+    // The path of the directory inside of the repo.
+    pub repo_dir: String,
+    // The location of the repo in the cache.
+    pub real_repo_path: String,
+    pub end_time: Option<DateTime<Utc>>,
+}
+
+pub struct Prefetcher {
+    sender: Mutex<Sender<Job>>,
+    // How many levels of subdirectories are listed below a directory.
+    depth: usize,
+    // Files up to this size are fetched along with the directories.
+    max_blob_size: u64,
+}
+
+impl Prefetcher {
+    pub fn new(depth: usize, max_blob_size: u64) -> Arc<Prefetcher> {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..PREFETCH_THREADS {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    // The filesystem has stopped.
+                    Err(_) => return,
+                };
+                job();
+            });
+        }
+        Arc::new(Prefetcher{
+            sender: Mutex::new(sender),
+            depth: depth,
+            max_blob_size: max_blob_size,
+        })
+    }
+
+    // Prefetches the children of a directory which was just listed.
+    pub fn prefetch(self: &Arc<Self>, host_fs: Arc<HostFS>, dir: PrefetchDir) {
+        if self.depth > 0 {
+            self.queue(host_fs, dir, self.depth);
+        }
+    }
+
+    fn queue(self: &Arc<Self>, host_fs: Arc<HostFS>, dir: PrefetchDir, depth: usize) {
+        let prefetcher = self.clone();
+        let job: Job = Box::new(move || prefetcher.run(&host_fs, &dir, depth));
+        if self.sender.lock().unwrap().send(job).is_err() {
+            println!("Prefetch threads have stopped");
+        }
+    }
+
+    fn run(self: &Arc<Self>, host_fs: &Arc<HostFS>, dir: &PrefetchDir, depth: usize) {
+        let local_dir = Path::new(&dir.real_repo_path).join(&dir.repo_dir);
+        let entries = match fs::read_dir(&local_dir) {
+            Ok(entries) => entries,
+            // This was a file rather than a directory.
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if dir.repo_dir.is_empty() && host_fs.is_metadata_dir(&dir.repo, &name) {
+                continue;
+            }
+            let path = match dir.repo_dir.as_str() {
+                "" => name,
+                _ => format!("{}/{}", dir.repo_dir, name),
+            };
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                println!("Prefetching directory {}/{}", dir.repo, path);
+                if let Err(e) = host_fs.clone_dir(&path, &dir.real_repo_path, &dir.repo, dir.end_time) {
+                    println!("Unable to prefetch {}: {:?}", path, e);
+                    continue;
+                }
+                if depth > 1 {
+                    let child = PrefetchDir{ repo_dir: path, ..dir.clone() };
+                    self.queue(host_fs.clone(), child, depth - 1);
+                }
+            } else if self.max_blob_size > 0
+                && metadata.len() <= self.max_blob_size
+                && host_fs.is_placeholder(&dir.repo, &path)
+                && !host_fs.is_unsized(&dir.repo, &path) {
+                println!("Prefetching file {}/{}", dir.repo, path);
+                if let Err(e) = host_fs.clone_dir(&path, &dir.real_repo_path, &dir.repo, dir.end_time) {
+                    println!("Unable to prefetch {}: {:?}", path, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+// The interface that every git hosting provider (Github, Gitlab, plain git servers, ...) implements.
+// Providers only talk to the remote. All caching of the results is done by HostFS.
+
+use chrono::{DateTime, Utc};
+use crate::error::{Result};
+use crate::smart_http::{SmartHttpFS};
+
+pub enum EntryKind {
+    // A file.
+    Blob,
+    // A directory.
+    Tree,
+}
+
+pub struct TreeEntry {
+    // The name of the entry relative to the tree which contains it.
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    // None if the provider does not know the size without downloading the blob.
+    pub size: Option<u64>,
+    pub sha: String,
+    // The contents of the file, if the provider returned them along with the listing.
+    pub content: Option<Vec<u8>>,
+}
+
+pub enum RefKind {
+    Branch,
+    Tag,
+}
+
+pub struct CommitInfo {
+    pub sha: String,
+    pub time: DateTime<Utc>,
+    pub message: String,
+}
+
+// The API quota of a provider. Some providers have a separate quota for each API.
+#[derive(Clone)]
+pub struct RateLimit {
+    pub resource: String,
+    pub limit: u64,
+    pub remaining: u64,
+    // The time at which the quota is refilled.
+    pub reset: DateTime<Utc>,
+}
+
+pub struct Tree {
+    pub sha: String,
+    pub entries: Vec<TreeEntry>,
+}
+
+// Providers are shared by all of the FUSE threads.
+pub trait RemoteProvider: Send + Sync {
+    // Returns the names of all repos which belong to a user.
+    fn list_user_repos(&self, user: &str) -> Result<Vec<String>>;
+
+    // Returns the sha of the most recent commit made before end_time which is reachable from
+    // reference (a branch, tag or sha, or the default branch if None). If end_time is None, the
+    // commit which reference currently points to is returned. Returns None if the repo does not
+    // have any commits (before end_time).
+    fn resolve_commit(&self, user: &str, repo: &str, reference: Option<&str>, end_time: Option<DateTime<Utc>>) -> Result<Option<String>>;
+
+    // Returns the entries of a tree. sha may be either a tree sha or a commit sha, in which case
+    // the root tree of the commit is listed.
+    fn list_tree(&self, user: &str, repo: &str, sha: &str) -> Result<Tree>;
+
+    // Returns every entry below a tree, with paths relative to it, or None if the provider can not
+    // list the whole tree at once.
+    fn list_tree_recursive(&self, _user: &str, _repo: &str, _sha: &str) -> Result<Option<Tree>> {
+        Ok(None)
+    }
+
+    // Returns up to count commits, starting at sha and following first parents.
+    fn list_commits(&self, user: &str, repo: &str, sha: &str, count: usize) -> Result<Vec<CommitInfo>>;
+
+    // Returns the names of all branches or tags of a repo.
+    fn list_refs(&self, user: &str, repo: &str, kind: RefKind) -> Result<Vec<String>>;
+
+    // Writes the contents of a blob to local_path.
+    fn fetch_blob(&self, user: &str, repo: &str, sha: &str, local_path: &str) -> Result<()>;
+
+    // Returns the URL used to clone a repo with git.
+    fn clone_url(&self, user: &str, repo: &str) -> String;
+
+    // Returns the client for the git protocol of the server which the repos are cloned from. It
+    // fetches the objects which git looks up in the .git directory of a repo.
+    fn git_remote(&self) -> &SmartHttpFS;
+
+    // Returns the index of the component of path (relative to the host) which names a repo, or
+    // None if path is a directory above the repos. Most providers use <user>/<repo>.
+    fn repo_index(&self, path: &[&str]) -> Option<usize> {
+        if path.len() > 1 {
+            Some(1)
+        } else {
+            None
+        }
+    }
+
+    // Returns false if list_user_repos can not list the repos of a user, in which case any user
+    // directory is assumed to exist.
+    fn can_list_repos(&self) -> bool {
+        true
+    }
+
+    // Returns the API quotas as of the last request, or nothing if the provider does not have a
+    // quota.
+    fn rate_limits(&self) -> Vec<RateLimit> {
+        Vec::new()
+    }
+}
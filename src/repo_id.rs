@@ -0,0 +1,39 @@
+// Identifies a repo directory: a repo of an owner on a host, at the revision named by the
+// directory. Repos with the same name which belong to different owners (such as forks) are
+// different repos, and so are the directories of the same repo at different revisions.
+
+use std::fmt;
+use crate::host::{Revision, split_repo_name};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RepoId {
+    pub host: String,
+    // The user or organization which owns the repo. It may contain slashes on hosts where repos
+    // are grouped.
+    pub owner: String,
+    // The name of the repo on the host.
+    pub name: String,
+    // The name of the repo directory, which is the name followed by the revision, if any.
+    pub dir_name: String,
+}
+
+impl RepoId {
+    pub fn new(host: &str, owner: &str, dir_name: &str) -> RepoId {
+        RepoId{
+            host: host.to_string(),
+            owner: owner.to_string(),
+            name: split_repo_name(dir_name).0.to_string(),
+            dir_name: dir_name.to_string(),
+        }
+    }
+
+    pub fn revision(&self) -> Revision {
+        split_repo_name(&self.dir_name).1
+    }
+}
+
+impl fmt::Display for RepoId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.host, self.owner, self.dir_name)
+    }
+}
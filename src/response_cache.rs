@@ -0,0 +1,62 @@
+// Stores the responses of API calls along with their ETag and Last-Modified headers, so that they
+// can be revalidated with a conditional request instead of being downloaded again. Github does
+// not count a 304 Not Modified response against the rate limit.
+
+use std::fs;
+use git2::{ObjectType, Oid};
+use serde_json::json;
+use serde_json::value::Value;
+use crate::error::{GitFSError, Result};
+use crate::journal;
+
+pub struct CachedResponse {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    // The URL of the next page of results, if there is one.
+    pub next_url: Option<String>,
+    pub body: String,
+}
+
+pub struct ResponseCache {
+    dir: String,
+}
+
+impl ResponseCache {
+    pub fn new(dir: &str) -> ResponseCache {
+        ResponseCache{
+            dir: dir.to_string(),
+        }
+    }
+
+    // Each response is stored in a file named after the sha of its URL.
+    fn path(&self, url: &str) -> Result<String> {
+        let sha = Oid::hash_object(ObjectType::Blob, url.as_bytes())?.to_string();
+        Ok(format!("{}/{}/{}", self.dir, &sha[..2], &sha[2..]))
+    }
+
+    pub fn get(&self, url: &str) -> Option<CachedResponse> {
+        let entry: Value = serde_json::from_str(&fs::read_to_string(self.path(url).ok()?).ok()?).ok()?;
+        if entry["url"].as_str()? != url {
+            return None;
+        }
+        Some(CachedResponse{
+            etag: entry["etag"].as_str().map(String::from),
+            last_modified: entry["last_modified"].as_str().map(String::from),
+            next_url: entry["next_url"].as_str().map(String::from),
+            body: entry["body"].as_str()?.to_string(),
+        })
+    }
+
+    pub fn put(&self, url: &str, response: &CachedResponse) -> Result<()> {
+        let path = self.path(url)?;
+        fs::create_dir_all(&path[..path.rfind('/').ok_or(GitFSError::NoneError)?])?;
+        let entry = json!({
+            "url": url,
+            "etag": response.etag,
+            "last_modified": response.last_modified,
+            "next_url": response.next_url,
+            "body": response.body,
+        });
+        journal::write_atomic(&path, entry.to_string().as_bytes())
+    }
+}
@@ -0,0 +1,96 @@
+// How requests to remote hosts are retried when they fail for a reason which is likely to be
+// temporary, such as a dropped connection or a server error. It is shared by every provider.
+
+use std::cmp;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use reqwest::StatusCode;
+use reqwest::blocking::Client;
+use crate::error::{GitFSError, Result};
+use crate::network::{Network};
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    // The number of times a request is made before its error is returned.
+    pub attempts: u32,
+    // The wait before the first retry. It doubles after each retry, up to max_backoff.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    // A request fails if it has not finished within this time, so that a hung connection does not
+    // block the filesystem forever.
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    // Returns true if a request which failed with the error should be retried.
+    pub is_retryable: fn(&GitFSError) -> bool,
+    // Shared by every copy of the policy, so that a host which can not be reached is known to
+    // every provider.
+    pub network: Arc<Network>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy{
+            attempts: 4,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            timeout: Duration::from_secs(300),
+            connect_timeout: Duration::from_secs(10),
+            is_retryable: is_transient,
+            network: Arc::new(Network::new()),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Returns a client which applies the timeouts of the policy.
+    pub fn client(&self) -> Client {
+        Client::builder()
+            .timeout(self.timeout)
+            .connect_timeout(self.connect_timeout)
+            .build()
+            .expect("Unable to create an HTTP client")
+    }
+
+    // Runs a request to url until it succeeds, fails with an error which is not retryable or has
+    // been tried the maximum number of times. request should send the request and read its
+    // response, so that a connection which drops while the response is read is retried as well.
+    // The request is not made at all if the host can not be reached.
+    pub fn run<T, F>(&self, url: &str, mut request: F) -> Result<T>
+        where F: FnMut() -> Result<T> {
+        self.network.check(url)?;
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match request() {
+                Err(ref e) if attempt < self.attempts && (self.is_retryable)(e) => {
+                    println!("{} failed ({}), retrying in {:?}", url, e, backoff);
+                },
+                Err(e) => return Err(self.network.report(url, e)),
+                result => return result,
+            }
+            thread::sleep(backoff);
+            backoff = cmp::min(backoff * 2, self.max_backoff);
+            attempt += 1;
+        }
+    }
+}
+
+// Returns true for network errors, timeouts and server errors. Errors such as a missing repo or a
+// bad token will not go away by trying again.
+pub fn is_transient(e: &GitFSError) -> bool {
+    match e {
+        GitFSError::ReqwestError(e) => match e.status() {
+            Some(status) => status.is_server_error() && status != StatusCode::NOT_IMPLEMENTED
+                || status == StatusCode::REQUEST_TIMEOUT,
+            None => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+        },
+        GitFSError::IOError(e) => matches!(e.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::UnexpectedEof),
+        _ => false,
+    }
+}
@@ -0,0 +1,1025 @@
+// A provider for any git server which speaks the smart HTTP protocol.
+//
+// Repos are addressed as <host>/<path>.git. The fetched objects of each repo are kept in a bare
+// repo under the objects directory. Commits are fetched without their blobs (filter=blob:none) so
+// that directory listings only need tree objects, and each blob is fetched the first time it is
+// used.
+//
+// Protocol version 2 is used if the server supports it. Older servers (versions 0 and 1, which
+// only differ in their advertisement) list every ref up front and get the same fetches. They may
+// refuse to send an object which no ref points to, such as a single blob, unless they allow any
+// sha to be wanted. A server which does not support an argument of a fetch (such as a filter or
+// a depth) gets the fetch without it, and so may send much more than was asked for. Packs are
+// streamed into the object database as they arrive, so they are never held in memory.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::{DateTime, TimeZone, Utc};
+use git2::{ObjectType, Odb, Oid, Repository};
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, USER_AGENT};
+use crate::error::{GitFSError, Result};
+use crate::libc_extras::libc;
+use crate::provider::{CommitInfo, EntryKind, RefKind, RemoteProvider, Tree, TreeEntry};
+use crate::retry::{RetryPolicy};
+
+// Some servers only speak protocol version 2 to clients which identify as git.
+const AGENT: &str = "git/2.0 (Virtual Git Filesystem)";
+
+// What a server advertises for a repo before any command is sent.
+#[derive(Clone)]
+struct Advertisement {
+    // 2, or 0 for both version 0 and version 1.
+    version: u8,
+    capabilities: Vec<String>,
+    // Version 2 servers list refs with the ls-refs command instead.
+    refs: Vec<RemoteRef>,
+}
+
+impl Advertisement {
+    // Returns true if the server supports a feature of the fetch command.
+    fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.iter().any(|capability| match self.version {
+            2 => capability.starts_with("fetch=") && capability["fetch=".len()..].split(' ').any(|feature| feature == name),
+            _ => capability == name || capability.starts_with(&format!("{}=", name)),
+        })
+    }
+
+    // Returns the capability which an argument of the fetch command needs. The deepen arguments
+    // need the shallow capability, which version 2 servers also list for deepen-since.
+    fn argument_capability<'a>(&self, arg: &'a str) -> &'a str {
+        match arg.split(' ').next().unwrap_or("").trim_end() {
+            "deepen" => "shallow",
+            name if self.version == 2 && name.starts_with("deepen-") => "shallow",
+            name => name,
+        }
+    }
+
+    fn supports_argument(&self, arg: &str) -> bool {
+        self.has_capability(self.argument_capability(arg))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct RemoteRef {
+    sha: String,
+    // The full name of the ref, e.g. refs/heads/master.
+    name: String,
+    // The commit which an annotated tag points to.
+    peeled: Option<String>,
+}
+
+// A pkt-line read from a stream.
+enum Packet {
+    Data(Vec<u8>),
+    // A flush, delimiter or response-end packet.
+    Special,
+}
+
+pub struct SmartHttpFS {
+    // The scheme and host of every repo URL.
+    base_url: String,
+    // Added to the name of a repo in its URL.
+    repo_suffix: String,
+    // Each repo gets a bare repo under this directory which holds the fetched objects.
+    objects_dir: String,
+    // Maps a repo URL to the advertisement of its server.
+    advertisements: Mutex<HashMap<String, Advertisement>>,
+    // Shared by every request so that connections to the server are reused.
+    client: Client,
+    retry: RetryPolicy,
+    // The username and password which requests are authenticated with, if any.
+    credentials: Option<(String, String)>,
+}
+
+impl SmartHttpFS {
+    pub fn new(host: &str, cache_dir: &str, retry: RetryPolicy) -> SmartHttpFS {
+        SmartHttpFS{
+            base_url: format!("https://{}", host),
+            repo_suffix: String::new(),
+            objects_dir: format!("{}/objects/{}", cache_dir, host),
+            advertisements: Mutex::new(HashMap::new()),
+            client: retry.client(),
+            retry: retry,
+            credentials: None,
+        }
+    }
+
+    pub fn set_credentials(&mut self, credentials: Option<(String, String)>) {
+        self.credentials = credentials;
+    }
+
+    // Addresses each repo as <base_url>/<user>/<repo><repo_suffix>, e.g. to fetch from the clone
+    // URLs of another provider.
+    pub fn set_base_url(&mut self, base_url: &str, repo_suffix: &str) {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self.repo_suffix = repo_suffix.to_string();
+    }
+
+    // Talks to the server over plain HTTP instead of HTTPS, e.g. for a local test server.
+    pub fn set_plain_http(&mut self, plain_http: bool) {
+        let host = self.base_url.split_once("://").map(|(_, host)| host).unwrap_or("").to_string();
+        let scheme = if plain_http { "http" } else { "https" };
+        self.base_url = format!("{}://{}", scheme, host);
+    }
+
+    fn authenticate(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.credentials {
+            Some((username, password)) => request.basic_auth(username, Some(password)),
+            None => request,
+        }
+    }
+
+    fn repo_url(&self, user: &str, repo: &str) -> String {
+        format!("{}/{}/{}{}", self.base_url, user, repo, self.repo_suffix)
+    }
+
+    // Opens the bare repo which holds the objects of a remote repo, creating it if needed.
+    pub fn open_repo(&self, user: &str, repo: &str) -> Result<Repository> {
+        let path = format!("{}/{}/{}", self.objects_dir, user, repo);
+        if Path::new(&path).exists() {
+            return Ok(Repository::open_bare(&path)?);
+        }
+        fs::create_dir_all(&path)?;
+        Ok(Repository::init_bare(&path)?)
+    }
+
+    // Returns the server's advertisement for a repo. It is only requested once per repo.
+    fn advertisement(&self, url: &str) -> Result<Advertisement> {
+        if let Some(advertisement) = self.advertisements.lock().unwrap().get(url) {
+            return Ok(advertisement.clone());
+        }
+        self.advertise(url)
+    }
+
+    // Requests the server's advertisement for a repo, replacing the one which was stored.
+    fn advertise(&self, url: &str) -> Result<Advertisement> {
+        let advertisement_url = format!("{}/info/refs?service=git-upload-pack", url);
+        println!("Request {}", advertisement_url);
+        let body = self.retry.run(&advertisement_url, || {
+            let resp = self.authenticate(self.client.get(&advertisement_url))
+                .header(USER_AGENT, AGENT)
+                .header("Git-Protocol", "version=2")
+                .send()?;
+            resp.error_for_status_ref()?;
+            Ok(resp.bytes()?)
+        })?;
+        let advertisement = parse_advertisement(&body)?;
+        self.advertisements.lock().unwrap().insert(url.to_string(), advertisement.clone());
+        Ok(advertisement)
+    }
+
+    // Makes sure that the server has a git repo at user/repo.
+    pub fn check_repo(&self, user: &str, repo: &str) -> Result<()> {
+        self.advertisement(&self.repo_url(user, repo))?;
+        Ok(())
+    }
+
+    // Returns true if the server can omit blobs from a fetch.
+    fn supports_filter(&self, url: &str) -> Result<bool> {
+        Ok(self.advertisement(url)?.has_capability("filter"))
+    }
+
+    // Returns the refs of a repo which start with any of prefixes.
+    fn list_remote_refs(&self, url: &str, prefixes: &[String]) -> Result<Vec<RemoteRef>> {
+        let is_stored = self.advertisements.lock().unwrap().contains_key(url);
+        let mut advertisement = self.advertisement(url)?;
+        let refs = if advertisement.version == 2 {
+            let mut args = vec!["peel\n".to_string()];
+            for prefix in prefixes {
+                args.push(format!("ref-prefix {}\n", prefix));
+            }
+            let response = self.upload_pack(url, command_request("ls-refs", &args), "ls-refs", |response| {
+                let mut data = Vec::new();
+                response.read_to_end(&mut data)?;
+                Ok(data)
+            })?;
+            parse_ls_refs(&response)?
+        } else {
+            // Refs move, so the advertisement which lists them is requested again.
+            if is_stored {
+                advertisement = self.advertise(url)?;
+            }
+            advertisement.refs
+        };
+        Ok(refs.into_iter().filter(|r| prefixes.iter().any(|prefix| r.name.starts_with(prefix))).collect())
+    }
+
+    // Sends a request to the upload-pack service of a repo and passes the response to read as it
+    // arrives. read is called again if the request is retried.
+    fn upload_pack<T, F>(&self, url: &str, body: String, description: &str, read: F) -> Result<T>
+        where F: Fn(&mut dyn Read) -> Result<T> {
+        let command_url = format!("{}/git-upload-pack", url);
+        println!("Request {} ({})", command_url, description);
+        self.retry.run(&command_url, || {
+            let mut resp = self.authenticate(self.client.post(&command_url))
+                .header(USER_AGENT, AGENT)
+                .header("Git-Protocol", "version=2")
+                .header(CONTENT_TYPE, "application/x-git-upload-pack-request")
+                .header(ACCEPT, "application/x-git-upload-pack-result")
+                .body(body.clone())
+                .send()?;
+            resp.error_for_status_ref()?;
+            read(&mut resp)
+        })
+    }
+
+    // Fetches an object into the repo's object database. If trees_only is set, the want is a
+    // commit or tree and all of the trees below it are fetched without any blobs.
+    fn fetch_object(&self, user: &str, repo: &str, sha: &str, trees_only: bool) -> Result<()> {
+        let mut args = Vec::new();
+        if trees_only {
+            // Only the wanted commit is needed, not its history.
+            args.push("deepen 1\n".to_string());
+            if self.supports_filter(&self.repo_url(user, repo))? {
+                args.push("filter blob:none\n".to_string());
+            }
+        }
+        self.fetch(user, repo, sha, args)
+    }
+
+    // Fetches the commits reachable from sha which were made after since, without their trees.
+    fn fetch_history(&self, user: &str, repo: &str, sha: &str, since: DateTime<Utc>) -> Result<()> {
+        let mut args = vec![format!("deepen-since {}\n", since.timestamp())];
+        if self.supports_filter(&self.repo_url(user, repo))? {
+            args.push("filter tree:0\n".to_string());
+        }
+        self.fetch(user, repo, sha, args)
+    }
+
+    // Fetches a commit and all of the trees below it, without any blobs or history.
+    pub fn fetch_trees(&self, user: &str, repo: &str, sha: &str) -> Result<()> {
+        if self.open_repo(user, repo)?.odb()?.exists(Oid::from_str(sha)?) {
+            return Ok(());
+        }
+        self.fetch_object(user, repo, sha, true)
+    }
+
+    // Fetches a single object of any type. A commit comes without its tree, which is fetched when
+    // it is needed. Returns false if the server does not have the object.
+    pub fn fetch_any_object(&self, user: &str, repo: &str, sha: &str) -> Result<bool> {
+        let git_repo = self.open_repo(user, repo)?;
+        let odb = git_repo.odb()?;
+        let oid = Oid::from_str(sha)?;
+        let unfiltered = vec!["deepen 1\n".to_string()];
+        let mut filtered = unfiltered.clone();
+        if self.supports_filter(&self.repo_url(user, repo))? {
+            filtered.push("filter tree:0\n".to_string());
+        }
+        // Some servers leave out a wanted tree or blob which the filter excludes, so it is asked
+        // for again without the filter. A commit still comes without its history.
+        for args in [filtered, unfiltered] {
+            if odb.exists(oid) {
+                return Ok(true);
+            }
+            if let Err(e) = self.fetch(user, repo, sha, args) {
+                if e.code() == libc::EHOSTUNREACH {
+                    return Err(e);
+                }
+                println!("Unable to fetch {} ({})", sha, e);
+            }
+        }
+        Ok(odb.exists(oid))
+    }
+
+    fn fetch(&self, user: &str, repo: &str, sha: &str, mut args: Vec<String>) -> Result<()> {
+        let url = self.repo_url(user, repo);
+        let advertisement = self.advertisement(&url)?;
+        args.retain(|arg| advertisement.supports_argument(arg));
+        let git_repo = self.open_repo(user, repo)?;
+        let odb = git_repo.odb()?;
+        if advertisement.version == 2 {
+            args.push("no-progress\n".to_string());
+            args.push("ofs-delta\n".to_string());
+            args.push(format!("want {}\n", sha));
+            args.push("done\n".to_string());
+            return self.upload_pack(&url, command_request("fetch", &args), "fetch", |response| {
+                store_pack(&odb, |pack| read_packfile(response, pack))
+            });
+        }
+        // Versions 0 and 1 ask for capabilities on the want line, including the ones which the
+        // arguments need.
+        let mut capabilities: Vec<&str> = ["side-band-64k", "ofs-delta", "no-progress"].iter()
+            .filter(|capability| advertisement.has_capability(capability))
+            .cloned()
+            .collect();
+        for arg in &args {
+            let capability = advertisement.argument_capability(arg);
+            if !capabilities.contains(&capability) {
+                capabilities.push(capability);
+            }
+        }
+        let mut body = pkt_line(&format!("want {} {}\n", sha, capabilities.join(" ")));
+        for arg in &args {
+            body.push_str(&pkt_line(arg));
+        }
+        body.push_str("0000");
+        body.push_str(&pkt_line("done\n"));
+        let sideband = capabilities.contains(&"side-band-64k");
+        self.upload_pack(&url, body, "fetch", |response| {
+            store_pack(&odb, |pack| read_packfile_v0(response, sideband, pack))
+        })
+    }
+
+    // Returns the commit which reference (or HEAD) points to. A branch is preferred over a tag
+    // with the same name.
+    fn resolve_ref(&self, user: &str, repo: &str, reference: Option<&str>) -> Result<String> {
+        let names = match reference {
+            // Full shas can be fetched directly. The protocol has no way to expand a short sha.
+            Some(sha) if sha.len() == 40 && sha.chars().all(|c| c.is_ascii_hexdigit()) => {
+                return Ok(sha.to_string());
+            },
+            Some(name) => vec![format!("refs/heads/{}", name), format!("refs/tags/{}", name)],
+            None => vec!["HEAD".to_string()],
+        };
+        let refs = self.list_remote_refs(&self.repo_url(user, repo), &names)?;
+        for name in &names {
+            if let Some(remote_ref) = refs.iter().find(|r| r.name == *name) {
+                // Annotated tags are resolved to the commit that they point to.
+                return Ok(remote_ref.peeled.clone().unwrap_or(remote_ref.sha.clone()));
+            }
+        }
+        // Either the ref does not exist or the repo is empty and does not have a HEAD.
+        Err(GitFSError::new("Not Found", libc::ENOENT))
+    }
+
+    // Walks back the first parents of head to find the latest commit made before end_time.
+    fn commit_before(&self, user: &str, repo: &str, head: &str, end_time: DateTime<Utc>) -> Result<Option<String>> {
+        let git_repo = self.open_repo(user, repo)?;
+        let mut oid = Oid::from_str(head)?;
+        if !git_repo.odb()?.exists(oid) {
+            self.fetch_object(user, repo, head, true)?;
+        }
+        let mut fetched_history = false;
+        loop {
+            if !git_repo.odb()?.exists(oid) {
+                // The history stops at the first commit made before end_time, so its parent is
+                // the commit that we are looking for.
+                if fetched_history {
+                    return Ok(Some(oid.to_string()));
+                }
+                self.fetch_history(user, repo, head, end_time)?;
+                fetched_history = true;
+                continue;
+            }
+            let commit = git_repo.find_commit(oid)?;
+            if commit.time().seconds() <= end_time.timestamp() {
+                return Ok(Some(oid.to_string()));
+            }
+            // A root commit made after end_time means that the repo did not have any commits yet.
+            oid = match commit.parent_id(0) {
+                Ok(oid) => oid,
+                Err(_) => return Ok(None),
+            };
+        }
+    }
+}
+
+impl RemoteProvider for SmartHttpFS {
+    fn list_user_repos(&self, _user: &str) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn resolve_commit(&self, user: &str, repo: &str, reference: Option<&str>, end_time: Option<DateTime<Utc>>) -> Result<Option<String>> {
+        let head = match self.resolve_ref(user, repo, reference) {
+            Ok(head) => head,
+            // An empty repo does not advertise a HEAD.
+            Err(ref e) if reference.is_none() && e.code() == libc::ENOENT => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match end_time {
+            Some(end_time) => self.commit_before(user, repo, &head, end_time),
+            None => Ok(Some(head)),
+        }
+    }
+
+    fn list_tree(&self, user: &str, repo: &str, sha: &str) -> Result<Tree> {
+        let git_repo = self.open_repo(user, repo)?;
+        let oid = Oid::from_str(sha)?;
+        if !git_repo.odb()?.exists(oid) {
+            self.fetch_object(user, repo, sha, true)?;
+        }
+        let tree = git_repo.find_object(oid, None)?.peel_to_tree()?;
+        let mut entries = Vec::new();
+        for entry in tree.iter() {
+            let kind = match entry.kind() {
+                Some(ObjectType::Blob) => EntryKind::Blob,
+                Some(ObjectType::Tree) => EntryKind::Tree,
+                kind => {
+                    eprintln!("Unknown type: {:?}", kind);
+                    continue;
+                }
+            };
+            entries.push(TreeEntry{
+                path: entry.name().ok_or(GitFSError::NoneError)?.to_string(),
+                kind: kind,
+                mode: entry.filemode() as u32,
+                // Blobs which have not been fetched yet have an unknown size.
+                size: git_repo.find_blob(entry.id()).ok().map(|blob| blob.size() as u64),
+                sha: entry.id().to_string(),
+                content: None,
+            });
+        }
+        Ok(Tree{
+            sha: tree.id().to_string(),
+            entries: entries,
+        })
+    }
+
+    fn list_commits(&self, user: &str, repo: &str, sha: &str, count: usize) -> Result<Vec<CommitInfo>> {
+        let mut args = vec![format!("deepen {}\n", count)];
+        if self.supports_filter(&self.repo_url(user, repo))? {
+            args.push("filter tree:0\n".to_string());
+        }
+        self.fetch(user, repo, sha, args)?;
+
+        let git_repo = self.open_repo(user, repo)?;
+        let mut commits = Vec::new();
+        let mut oid = Oid::from_str(sha)?;
+        while commits.len() < count && git_repo.odb()?.exists(oid) {
+            let commit = git_repo.find_commit(oid)?;
+            commits.push(CommitInfo{
+                sha: oid.to_string(),
+                time: Utc.timestamp_opt(commit.time().seconds(), 0).single().ok_or(GitFSError::NoneError)?,
+                message: commit.message().unwrap_or("").to_string(),
+            });
+            oid = match commit.parent_id(0) {
+                Ok(parent) => parent,
+                // This is the root commit.
+                Err(_) => break,
+            };
+        }
+        Ok(commits)
+    }
+
+    fn list_refs(&self, user: &str, repo: &str, kind: RefKind) -> Result<Vec<String>> {
+        let prefix = match kind {
+            RefKind::Branch => "refs/heads/",
+            RefKind::Tag => "refs/tags/",
+        };
+        let refs = self.list_remote_refs(&self.repo_url(user, repo), &[prefix.to_string()])?;
+        Ok(refs.iter().map(|r| r.name[prefix.len()..].to_string()).collect())
+    }
+
+    fn fetch_blob(&self, user: &str, repo: &str, sha: &str, local_path: &str) -> Result<()> {
+        let git_repo = self.open_repo(user, repo)?;
+        let oid = Oid::from_str(sha)?;
+        if !git_repo.odb()?.exists(oid) {
+            self.fetch_object(user, repo, sha, false)?;
+        }
+        let blob = git_repo.find_blob(oid)?;
+        fs::write(local_path, blob.content())?;
+        Ok(())
+    }
+
+    fn clone_url(&self, user: &str, repo: &str) -> String {
+        self.repo_url(user, repo)
+    }
+
+    fn git_remote(&self) -> &SmartHttpFS {
+        self
+    }
+
+    // The repo is the first component that ends in .git, ignoring any @<ref> suffix.
+    fn repo_index(&self, path: &[&str]) -> Option<usize> {
+        path.iter().position(|component| {
+            let name = component.split('@').next().unwrap_or("");
+            name.len() > ".git".len() && name.ends_with(".git")
+        })
+    }
+
+    fn can_list_repos(&self) -> bool {
+        false
+    }
+}
+
+fn invalid_response(msg: &str) -> GitFSError {
+    GitFSError::new(&format!("Invalid response from git server: {}", msg), libc::EIO)
+}
+
+fn pkt_line(data: &str) -> String {
+    format!("{:04x}{}", data.len() + 4, data)
+}
+
+// Returns the body of a request which runs a protocol version 2 command.
+fn command_request(command: &str, args: &[String]) -> String {
+    let mut body = pkt_line(&format!("command={}\n", command));
+    body.push_str(&pkt_line(&format!("agent={}\n", AGENT)));
+    body.push_str("0001");
+    for arg in args {
+        body.push_str(&pkt_line(arg));
+    }
+    body.push_str("0000");
+    body
+}
+
+// Parses the length of a pkt-line from its header, which is 4 hex digits.
+fn pkt_line_length(header: &[u8]) -> Result<usize> {
+    let len_hex = std::str::from_utf8(header).map_err(|_| invalid_response("bad pkt-line length"))?;
+    usize::from_str_radix(len_hex, 16).map_err(|_| invalid_response("bad pkt-line length"))
+}
+
+// Reads the pkt-line at the start of data. Returns the line, which is None for flush, delimiter
+// and response-end packets, and its length including the header.
+fn read_pkt_line(data: &[u8]) -> Result<(Option<&[u8]>, usize)> {
+    let len = pkt_line_length(data.get(..4).ok_or(GitFSError::NoneError)?)?;
+    if len < 4 {
+        return Ok((None, 4));
+    }
+    Ok((Some(data.get(4..len).ok_or(GitFSError::NoneError)?), len))
+}
+
+// Reads the next pkt-line from a stream. Returns None at the end of the stream.
+fn next_packet(reader: &mut dyn Read) -> Result<Option<Packet>> {
+    let mut header = [0; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(invalid_response("truncated pkt-line")),
+            n => filled += n,
+        }
+    }
+    let len = pkt_line_length(&header)?;
+    if len < 4 {
+        return Ok(Some(Packet::Special));
+    }
+    let mut data = vec![0; len - 4];
+    reader.read_exact(&mut data)?;
+    Ok(Some(Packet::Data(data)))
+}
+
+// Splits data into pkt-lines. Flush, delimiter and response-end packets are returned as None.
+fn read_pkt_lines(data: &[u8]) -> Result<Vec<Option<&[u8]>>> {
+    let mut lines = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (line, len) = read_pkt_line(&data[pos..])?;
+        lines.push(line);
+        pos += len;
+    }
+    Ok(lines)
+}
+
+// Parses the response to a GET of info/refs. Version 2 servers only list their capabilities.
+// Older servers list every ref, with the capabilities after the first one.
+fn parse_advertisement(body: &[u8]) -> Result<Advertisement> {
+    let mut advertisement = Advertisement{
+        version: 0,
+        capabilities: Vec::new(),
+        refs: Vec::new(),
+    };
+    for line in read_pkt_lines(body)?.into_iter().flatten() {
+        let line = String::from_utf8_lossy(line).trim_end().to_string();
+        if advertisement.version == 2 {
+            advertisement.capabilities.push(line);
+            continue;
+        }
+        // Servers may precede the advertisement with a "# service" header.
+        if line.starts_with("# service=") || line == "version 1" {
+            continue;
+        }
+        if line == "version 2" {
+            advertisement.version = 2;
+            continue;
+        }
+        let (ref_line, capabilities) = match line.find('\0') {
+            Some(i) => (&line[..i], Some(&line[i + 1..])),
+            None => (&line[..], None),
+        };
+        if let Some(capabilities) = capabilities {
+            advertisement.capabilities = capabilities.split(' ').map(|c| c.to_string()).collect();
+        }
+        let mut fields = ref_line.splitn(2, ' ');
+        let sha = fields.next().ok_or(GitFSError::NoneError)?.to_string();
+        let name = fields.next().ok_or(GitFSError::NoneError)?;
+        // An empty repo only advertises its capabilities.
+        if name == "capabilities^{}" {
+            continue;
+        }
+        // An annotated tag is followed by the commit that it points to.
+        if let Some(tag_name) = name.strip_suffix("^{}") {
+            if let Some(tag) = advertisement.refs.last_mut() {
+                if tag.name == tag_name {
+                    tag.peeled = Some(sha);
+                }
+            }
+            continue;
+        }
+        advertisement.refs.push(RemoteRef{
+            sha: sha,
+            name: name.to_string(),
+            peeled: None,
+        });
+    }
+    Ok(advertisement)
+}
+
+// Parses the response to the ls-refs command, which lists a ref per line as
+// <sha> <name> [peeled:<sha>].
+fn parse_ls_refs(response: &[u8]) -> Result<Vec<RemoteRef>> {
+    let mut refs = Vec::new();
+    for line in read_pkt_lines(response)?.into_iter().flatten() {
+        let line = String::from_utf8_lossy(line);
+        let mut fields = line.trim_end().split(' ');
+        let sha = fields.next().ok_or(GitFSError::NoneError)?.to_string();
+        let name = fields.next().ok_or(GitFSError::NoneError)?.to_string();
+        let peeled = fields.find(|attribute| attribute.starts_with("peeled:"))
+            .map(|attribute| attribute["peeled:".len()..].to_string());
+        refs.push(RemoteRef{
+            sha: sha,
+            name: name,
+            peeled: peeled,
+        });
+    }
+    Ok(refs)
+}
+
+// Writes the data of a pkt-line from a sideband to pack, printing progress messages.
+fn read_sideband(line: &[u8], pack: &mut dyn Write) -> Result<()> {
+    match line.first() {
+        Some(1) => pack.write_all(&line[1..])?,
+        Some(2) => println!("remote: {}", String::from_utf8_lossy(&line[1..]).trim_end()),
+        Some(3) => return Err(invalid_response(&String::from_utf8_lossy(&line[1..]))),
+        _ => return Err(invalid_response("unknown sideband")),
+    }
+    Ok(())
+}
+
+// Writes the pack in the response to a fetch command to pack, skipping all other sections.
+fn read_packfile(response: &mut dyn Read, pack: &mut dyn Write) -> Result<()> {
+    let mut in_packfile = false;
+    while let Some(packet) = next_packet(response)? {
+        let line = match packet {
+            Packet::Data(line) => line,
+            Packet::Special => continue,
+        };
+        if !in_packfile {
+            if line.starts_with(b"ERR ") {
+                return Err(invalid_response(&String::from_utf8_lossy(&line)));
+            }
+            in_packfile = line == b"packfile\n";
+            continue;
+        }
+        // The first byte of each line in the packfile section is the sideband.
+        read_sideband(&line, pack)?;
+    }
+    if !in_packfile {
+        return Err(invalid_response("missing packfile"));
+    }
+    Ok(())
+}
+
+// Writes the pack in the response to a version 0 or 1 fetch to pack. The pack follows the
+// shallow lines and the NAK, in pkt-lines with a sideband byte if side-band-64k was asked for and
+// as is otherwise.
+fn read_packfile_v0(response: &mut dyn Read, sideband: bool, pack: &mut dyn Write) -> Result<()> {
+    loop {
+        match next_packet(response)? {
+            None => return Err(invalid_response("missing packfile")),
+            Some(Packet::Data(line)) if line.starts_with(b"ERR ") => return Err(invalid_response(&String::from_utf8_lossy(&line))),
+            Some(Packet::Data(line)) if line.starts_with(b"NAK") || line.starts_with(b"ACK") => break,
+            // Shallow lines end with a flush.
+            _ => continue,
+        }
+    }
+    if !sideband {
+        io::copy(response, pack)?;
+        return Ok(());
+    }
+    while let Some(packet) = next_packet(response)? {
+        if let Packet::Data(line) = packet {
+            read_sideband(&line, pack)?;
+        }
+    }
+    Ok(())
+}
+
+// Adds the pack which write_pack writes to the object database. libgit2 indexes the pack as it
+// is written, resolves its deltas and checks its trailer before the pack is added.
+fn store_pack<F>(odb: &Odb, write_pack: F) -> Result<()>
+    where F: FnOnce(&mut dyn Write) -> Result<()> {
+    let mut writer = odb.packwriter()?;
+    write_pack(&mut writer)?;
+    writer.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::thread;
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+    use git2::{Signature, Time};
+    use rouille::cgi::CgiRun;
+    use tempdir::TempDir;
+
+    // Encodes an object of a pack. prefix is the base of a delta.
+    fn pack_object(pack_type: u8, prefix: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut size = data.len();
+        let mut byte = (pack_type << 4) | (size & 0x0f) as u8;
+        size >>= 4;
+        let mut object = Vec::new();
+        while size > 0 {
+            object.push(byte | 0x80);
+            byte = (size & 0x7f) as u8;
+            size >>= 7;
+        }
+        object.push(byte);
+        object.extend_from_slice(prefix);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        object.extend(encoder.finish().unwrap());
+        object
+    }
+
+    fn pack(objects: &[Vec<u8>]) -> Vec<u8> {
+        let mut pack = b"PACK".to_vec();
+        pack.extend_from_slice(&2u32.to_be_bytes());
+        pack.extend_from_slice(&(objects.len() as u32).to_be_bytes());
+        for object in objects {
+            pack.extend_from_slice(object);
+        }
+        // The trailer is the SHA-1 of everything before it.
+        let trailer = sha1_smol::Sha1::from(&pack).digest().bytes();
+        pack.extend_from_slice(&trailer);
+        pack
+    }
+
+    fn store(repo: &Repository, pack: &[u8]) -> Result<()> {
+        store_pack(&repo.odb().unwrap(), |writer| Ok(writer.write_all(pack)?))
+    }
+
+    fn packfile(response: &str) -> Result<Vec<u8>> {
+        let mut pack = Vec::new();
+        read_packfile(&mut response.as_bytes(), &mut pack)?;
+        Ok(pack)
+    }
+
+    fn packfile_v0(response: &str, sideband: bool) -> Result<Vec<u8>> {
+        let mut pack = Vec::new();
+        read_packfile_v0(&mut response.as_bytes(), sideband, &mut pack)?;
+        Ok(pack)
+    }
+
+    // A delta from "hello world\n" to "hello there\n", which copies "hello " from the base.
+    fn hello_delta() -> Vec<u8> {
+        let mut delta = vec![12, 12, 0x90, 6, 6];
+        delta.extend_from_slice(b"there\n");
+        delta
+    }
+
+    fn blob_oid(data: &[u8]) -> Oid {
+        Oid::hash_object(ObjectType::Blob, data).unwrap()
+    }
+
+    #[test]
+    fn reads_pkt_lines() {
+        let data = b"000ahello\n00000001000fworld again";
+        let lines = read_pkt_lines(data).unwrap();
+        assert_eq!(lines, vec![Some(&b"hello\n"[..]), None, None, Some(&b"world again"[..])]);
+    }
+
+    #[test]
+    fn rejects_truncated_pkt_lines() {
+        assert!(read_pkt_lines(b"000ahel").is_err());
+        assert!(read_pkt_lines(b"zzzz").is_err());
+    }
+
+    #[test]
+    fn reads_packfile_section() {
+        let mut response = pkt_line("acknowledgments\n");
+        response.push_str(&pkt_line("NAK\n"));
+        response.push_str("0001");
+        response.push_str(&pkt_line("packfile\n"));
+        response.push_str(&pkt_line("\u{1}PACK"));
+        response.push_str(&pkt_line("\u{2}counting objects\n"));
+        response.push_str(&pkt_line("\u{1}rest"));
+        response.push_str("0000");
+        assert_eq!(packfile(&response).unwrap(), b"PACKrest".to_vec());
+    }
+
+    #[test]
+    fn reports_packfile_errors() {
+        let response = pkt_line("ERR upload-pack: not our ref\n");
+        assert!(packfile(&response).is_err());
+        let response = pkt_line("acknowledgments\n") + "0000";
+        assert!(packfile(&response).is_err());
+        let response = pkt_line("packfile\n") + &pkt_line("\u{3}out of memory\n");
+        assert!(packfile(&response).is_err());
+    }
+
+    #[test]
+    fn reads_version_0_packfile() {
+        let mut response = pkt_line("shallow 0123456789012345678901234567890123456789\n");
+        response.push_str("0000");
+        response.push_str(&pkt_line("NAK\n"));
+        let raw = format!("{}PACKraw", response);
+        assert_eq!(packfile_v0(&raw, false).unwrap(), b"PACKraw".to_vec());
+        response.push_str(&pkt_line("\u{1}PACK"));
+        response.push_str(&pkt_line("\u{1}band"));
+        response.push_str("0000");
+        assert_eq!(packfile_v0(&response, true).unwrap(), b"PACKband".to_vec());
+    }
+
+    #[test]
+    fn parses_version_2_advertisement() {
+        let body = pkt_line("# service=git-upload-pack\n") + "0000" + &pkt_line("version 2\n")
+            + &pkt_line("ls-refs\n") + &pkt_line("fetch=shallow filter\n") + "0000";
+        let advertisement = parse_advertisement(body.as_bytes()).unwrap();
+        assert_eq!(advertisement.version, 2);
+        assert!(advertisement.has_capability("filter"));
+        assert!(advertisement.has_capability("shallow"));
+        assert!(!advertisement.has_capability("ls-refs"));
+    }
+
+    #[test]
+    fn checks_fetch_arguments() {
+        let version_2 = Advertisement{version: 2, capabilities: vec!["fetch=filter".to_string()], refs: Vec::new()};
+        assert!(version_2.supports_argument("filter blob:none\n"));
+        assert!(!version_2.supports_argument("deepen 1\n"));
+        assert!(!version_2.supports_argument("deepen-since 1577836800\n"));
+        let version_0 = Advertisement{version: 0, capabilities: vec!["shallow".to_string()], refs: Vec::new()};
+        assert!(version_0.supports_argument("deepen 1\n"));
+        assert!(!version_0.supports_argument("deepen-since 1577836800\n"));
+        assert!(!version_0.supports_argument("filter tree:0\n"));
+    }
+
+    #[test]
+    fn parses_version_0_advertisement() {
+        let head = "1111111111111111111111111111111111111111";
+        let tag = "2222222222222222222222222222222222222222";
+        let body = pkt_line("# service=git-upload-pack\n") + "0000"
+            + &pkt_line(&format!("{} HEAD\0multi_ack side-band-64k shallow agent=git/2.1\n", head))
+            + &pkt_line(&format!("{} refs/heads/master\n", head))
+            + &pkt_line(&format!("{} refs/tags/v1\n", tag))
+            + &pkt_line(&format!("{} refs/tags/v1^{{}}\n", head))
+            + "0000";
+        let advertisement = parse_advertisement(body.as_bytes()).unwrap();
+        assert_eq!(advertisement.version, 0);
+        assert!(advertisement.has_capability("side-band-64k"));
+        assert!(advertisement.has_capability("agent"));
+        assert!(!advertisement.has_capability("filter"));
+        assert_eq!(advertisement.refs.len(), 3);
+        assert_eq!(advertisement.refs[2], RemoteRef{
+            sha: tag.to_string(),
+            name: "refs/tags/v1".to_string(),
+            peeled: Some(head.to_string()),
+        });
+    }
+
+    #[test]
+    fn parses_empty_version_0_advertisement() {
+        let body = pkt_line(&format!("{} capabilities^{{}}\0shallow\n", "0".repeat(40))) + "0000";
+        let advertisement = parse_advertisement(body.as_bytes()).unwrap();
+        assert!(advertisement.refs.is_empty());
+        assert!(advertisement.has_capability("shallow"));
+    }
+
+    #[test]
+    fn parses_ls_refs() {
+        let response = pkt_line("1111111111111111111111111111111111111111 HEAD\n")
+            + &pkt_line("2222222222222222222222222222222222222222 refs/tags/v1 peeled:1111111111111111111111111111111111111111\n")
+            + "0000";
+        let refs = parse_ls_refs(response.as_bytes()).unwrap();
+        assert_eq!(refs[0].name, "HEAD");
+        assert_eq!(refs[0].peeled, None);
+        assert_eq!(refs[1].peeled.as_deref(), Some("1111111111111111111111111111111111111111"));
+    }
+
+    #[test]
+    fn stores_pack() {
+        let dir = TempDir::new("vg").unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let base = pack_object(3, &[], b"hello world\n");
+        // The offset delta refers back to the blob at the start of the pack.
+        let ofs_delta = pack_object(6, &[base.len() as u8], &hello_delta());
+        store(&repo, &pack(&[base, ofs_delta])).unwrap();
+        let odb = repo.odb().unwrap();
+        assert!(odb.exists(blob_oid(b"hello world\n")));
+        assert_eq!(odb.read(blob_oid(b"hello there\n")).unwrap().data(), b"hello there\n");
+    }
+
+    #[test]
+    fn stores_ref_delta_before_its_base() {
+        let dir = TempDir::new("vg").unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let ref_delta = pack_object(7, blob_oid(b"hello world\n").as_bytes(), &hello_delta());
+        let base = pack_object(3, &[], b"hello world\n");
+        store(&repo, &pack(&[ref_delta, base])).unwrap();
+        assert!(repo.odb().unwrap().exists(blob_oid(b"hello there\n")));
+    }
+
+    #[test]
+    fn rejects_missing_delta_base() {
+        let dir = TempDir::new("vg").unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let ref_delta = pack_object(7, blob_oid(b"hello world\n").as_bytes(), &hello_delta());
+        assert!(store(&repo, &pack(&[ref_delta])).is_err());
+        assert!(store(&repo, b"JUNK").is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_pack() {
+        let dir = TempDir::new("vg").unwrap();
+        let repo = Repository::init_bare(dir.path()).unwrap();
+        let mut corrupt = pack(&[pack_object(3, &[], b"hello world\n")]);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        assert!(store(&repo, &corrupt).is_err());
+        assert!(!repo.odb().unwrap().exists(blob_oid(b"hello world\n")));
+    }
+
+    // The objects of the repo which create_server_repo creates.
+    struct ServerRepo {
+        commit: Oid,
+        readme: Oid,
+    }
+
+    // Creates a bare repo with a README and src/main.rs on master, and an annotated tag v1.
+    fn create_server_repo(path: &Path) -> ServerRepo {
+        let repo = Repository::init_bare(path).unwrap();
+        let readme = repo.blob(b"hello world\n").unwrap();
+        let mut src = repo.treebuilder(None).unwrap();
+        src.insert("main.rs", repo.blob(b"fn main() {}\n").unwrap(), 0o100644).unwrap();
+        let mut root = repo.treebuilder(None).unwrap();
+        root.insert("README", readme, 0o100644).unwrap();
+        root.insert("src", src.write().unwrap(), 0o040000).unwrap();
+        let tree = repo.find_tree(root.write().unwrap()).unwrap();
+        let signature = Signature::new("Test", "test@example.com", &Time::new(1577836800, 0)).unwrap();
+        let commit = repo.commit(Some("refs/heads/master"), &signature, &signature, "Initial commit", &tree, &[]).unwrap();
+        repo.set_head("refs/heads/master").unwrap();
+        repo.tag("v1", &repo.find_object(commit, None).unwrap(), &signature, "Version 1", false).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_bool("uploadpack.allowFilter", true).unwrap();
+        config.set_bool("uploadpack.allowAnySHA1InWant", true).unwrap();
+        ServerRepo{
+            commit: commit,
+            readme: readme,
+        }
+    }
+
+    // Serves the repos under root with git http-backend, the CGI program behind git's own smart
+    // HTTP server. Protocol version 2 is only passed on to it if version_2 is set. Returns the
+    // address of the server.
+    fn serve(root: &Path, version_2: bool) -> String {
+        let root = root.to_path_buf();
+        let server = rouille::Server::new("127.0.0.1:0", move |request| {
+            let mut backend = Command::new("git");
+            backend.arg("http-backend")
+                .env("GIT_PROJECT_ROOT", &root)
+                .env("GIT_HTTP_EXPORT_ALL", "1");
+            if let (true, Some(protocol)) = (version_2, request.header("Git-Protocol")) {
+                backend.env("HTTP_GIT_PROTOCOL", protocol);
+            }
+            backend.start_cgi(request).unwrap()
+        }).unwrap();
+        let address = server.server_addr().to_string();
+        thread::spawn(move || server.run());
+        address
+    }
+
+    fn fetch_from_http_backend(version_2: bool) {
+        let dir = TempDir::new("vg").unwrap();
+        let server_repo = create_server_repo(&dir.path().join("server/octocat/hello.git"));
+        let commit = server_repo.commit.to_string();
+        let host = serve(&dir.path().join("server"), version_2);
+        let mut remote = SmartHttpFS::new(&host, dir.path().join("cache").to_str().unwrap(), RetryPolicy::default());
+        remote.set_plain_http(true);
+
+        assert_eq!(remote.resolve_commit("octocat", "hello.git", None, None).unwrap(), Some(commit.clone()));
+        assert_eq!(remote.resolve_commit("octocat", "hello.git", Some("v1"), None).unwrap(), Some(commit.clone()));
+        assert!(remote.resolve_commit("octocat", "hello.git", Some("missing"), None).is_err());
+        assert_eq!(remote.list_refs("octocat", "hello.git", RefKind::Branch).unwrap(), vec!["master"]);
+        assert_eq!(remote.list_refs("octocat", "hello.git", RefKind::Tag).unwrap(), vec!["v1"]);
+
+        let tree = remote.list_tree("octocat", "hello.git", &commit).unwrap();
+        let names: Vec<&str> = tree.entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(names, vec!["README", "src"]);
+        // The blobs were filtered out of the fetch.
+        assert_eq!(tree.entries[0].size, None);
+        let readme_path = dir.path().join("README");
+        remote.fetch_blob("octocat", "hello.git", &server_repo.readme.to_string(), readme_path.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read(&readme_path).unwrap(), b"hello world\n");
+
+        let commits = remote.list_commits("octocat", "hello.git", &commit, 5).unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].message, "Initial commit");
+        assert!(remote.check_repo("octocat", "missing.git").is_err());
+    }
+
+    #[test]
+    fn fetches_from_http_backend_with_version_2() {
+        fetch_from_http_backend(true);
+    }
+
+    #[test]
+    fn fetches_from_http_backend_with_version_0() {
+        fetch_from_http_backend(false);
+    }
+}